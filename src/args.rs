@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 /// Tools for export and import CIRCUS CRX files.
 #[derive(Parser, Debug)]
@@ -10,6 +10,14 @@ pub struct Arg {
     pub command: Option<Command>,
     #[clap(flatten)]
     pub auto: Option<AutoArgs>,
+    #[clap(flatten)]
+    /// Options used by auto mode when importing images. Kept out of [AutoArgs]
+    /// because clap can not detect an optional flattened group that contains
+    /// another flattened group.
+    pub import: ImportArgs,
+    #[arg(long, value_enum, default_value_t, global = true)]
+    /// Text encoding of entry names in PCK files
+    pub encoding: crate::pck::NameEncoding,
 }
 
 #[derive(Subcommand, Debug)]
@@ -19,18 +27,87 @@ pub enum Command {
     Export {
         /// Input CRX file
         input: String,
-        /// Output path to exported PNG file
+        /// Output path to exported image file (png, tga, bmp, qoi or webp)
         output: String,
+        #[arg(long, value_enum, default_value_t)]
+        /// Where to store CRX metadata
+        metadata: crate::metadata::MetadataMode,
+        #[arg(long)]
+        /// Export the alpha channel of a 32bpp CRX file as a grayscale mask image to
+        /// this path, the output image then only contains color
+        mask: Option<String>,
+        #[arg(long, conflicts_with = "mask")]
+        /// Place the image at its offset on a canvas starting at the screen origin,
+        /// undoing the trimming done by import --trim
+        untrim: bool,
+        #[arg(long, value_parser = parse_size, conflicts_with_all = ["mask", "untrim"])]
+        /// Place the image at its offset on a canvas of this size (WxH, e.g. 1920x1080)
+        /// starting at the screen origin
+        canvas: Option<(u32, u32)>,
     },
-    /// Import PNG to CRX files
+    /// Import images to CRX files
     Import {
         /// Original CRX file
         origin: String,
-        /// PNG file to import
+        /// Image file to import (png, tga, bmp, qoi or webp)
+        input: String,
+        /// Output path for the new CRX file
+        output: String,
+        #[arg(long)]
+        /// Grayscale mask image used as the alpha channel of the imported image
+        mask: Option<String>,
+        #[arg(long, value_parser = parse_position, allow_hyphen_values = true, conflicts_with_all = ["mask", "resize", "trim"])]
+        /// Only replace the region covered by the image, with its top left corner at X,Y
+        at: Option<(i32, i32)>,
+        #[arg(long, requires = "at")]
+        /// Blend the region over the original image using its alpha
+        blend: bool,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Create a new CRX file from an image file
+    Create(CreateArgs),
+    /// Export the clip table of a CRX file as JSON
+    ExportClips {
+        /// Input CRX file
+        input: String,
+        /// Output path to the JSON file, defaults to the input with a .clips.json
+        /// extension
+        output: Option<String>,
+        #[arg(long)]
+        /// Also export the region of every clip as a PNG file in this directory
+        images: Option<String>,
+    },
+    /// Replace the clip table of a CRX file with clips from a JSON file
+    ImportClips {
+        /// Original CRX file
+        origin: String,
+        /// JSON file containing the clips
         input: String,
         /// Output path for the new CRX file
         output: String,
     },
+    /// Draw CRX files on top of each other at their offsets into one image
+    Compose {
+        /// Output image file (png, tga, bmp, qoi or webp)
+        output: String,
+        /// CRX files from bottom to top. Files inside a PCK file are given as
+        /// path/to/file.pck/name.crx
+        layers: Vec<String>,
+        #[arg(long)]
+        /// JSON file listing the canvas size and the layers, drawn below the layers
+        /// given on the command line
+        manifest: Option<String>,
+        #[arg(long, value_parser = parse_size)]
+        /// Canvas size (WxH) starting at the screen origin, defaults to the manifest or
+        /// the area covered by all layers
+        canvas: Option<(u32, u32)>,
+    },
+    /// Show header information of CRX files
+    Info {
+        /// CRX file, or PCK file containing CRX files
+        input: String,
+    },
     /// Unpack PCK files
     Unpack {
         /// Input PCK file to unpack
@@ -42,15 +119,190 @@ pub enum Command {
     Pack {
         /// Input directory containing files to pack
         input: String,
-        /// Output PCK file
+        /// Output PCK file, or - to write to standard output
         output: String,
     },
 }
 
+#[derive(Args, Debug)]
+/// Arguments of the create command
+pub struct CreateArgs {
+    /// Image file to convert (png, tga, bmp, qoi or webp)
+    pub input: String,
+    /// Output path for the new CRX file
+    pub output: String,
+    #[arg(long, value_parser = clap::value_parser!(i16).range(2..=3))]
+    /// CRX version, defaults to the image metadata or 2
+    pub version: Option<i16>,
+    #[arg(long, default_value_t = 32, value_parser = parse_bpp)]
+    /// Bits per pixel
+    pub bpp: u8,
+    #[arg(long, allow_negative_numbers = true)]
+    /// X offset of the image, defaults to the image metadata or 0
+    pub inner_x: Option<i16>,
+    #[arg(long, allow_negative_numbers = true)]
+    /// Y offset of the image, defaults to the image metadata or 0
+    pub inner_y: Option<i16>,
+    #[arg(long = "clip", allow_negative_numbers = true)]
+    /// Clip entry as field_0,field_4,field_6,field_8,field_a,field_c,field_e, replaces
+    /// the clips in the image metadata (version 3 only, repeatable)
+    pub clips: Vec<crate::crx::Clip>,
+    #[arg(long, default_value = "heuristic", value_parser = parse_new_row_filter)]
+    /// How to choose the row type of each row (heuristic or best)
+    pub row_filter: crate::crx::RowFilter,
+    #[arg(long, value_enum, default_value = "zlib")]
+    /// Compression codec
+    pub codec: crate::utils::Codec,
+    #[arg(long, allow_negative_numbers = true)]
+    /// Compression level, defaults to the highest level of the codec
+    pub level: Option<i32>,
+    #[arg(long)]
+    /// Crop fully transparent borders of 32bpp images and move the image offset so the
+    /// sprite stays in place. Images without metadata are treated as a full canvas
+    /// starting at the screen origin. 24bpp images can not be trimmed
+    pub trim: bool,
+    #[arg(long, value_parser = parse_matte_color)]
+    /// Composite semi-transparent pixels over this color (#rrggbb) when creating a
+    /// 24bpp CRX file
+    pub matte: Option<crate::crx::Matte>,
+    #[arg(long, value_enum, default_value_t)]
+    /// What to do with the color of fully transparent pixels when creating a 32bpp CRX file
+    pub transparent: crate::crx::Transparent,
+}
+
+impl CreateArgs {
+    /// Convert to options used by [crate::crx::Crx::from_image]
+    pub fn to_options(&self) -> crate::crx::ImportOptions {
+        crate::crx::ImportOptions {
+            row_filter: self.row_filter,
+            codec: Some(self.codec),
+            level: self.level,
+            trim: self.trim,
+            matte: self.matte,
+            transparent: self.transparent,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct AutoArgs {
     /// Export/Import CRX files
     pub input: String,
+    #[arg(long, value_enum, default_value_t)]
+    /// Where to store CRX metadata of exported images
+    pub metadata: crate::metadata::MetadataMode,
+    #[arg(long, value_enum, default_value_t)]
+    /// Image format of exported images
+    pub format: crate::utils::ImageFormat,
+    #[arg(long, value_parser = parse_size)]
+    /// Place exported images at their offset on a canvas of this size (WxH, e.g.
+    /// 1920x1080) starting at the screen origin
+    pub canvas: Option<(u32, u32)>,
+}
+
+#[derive(Args, Debug)]
+/// Options used when importing images to CRX files
+pub struct ImportArgs {
+    #[arg(long, value_enum, default_value_t)]
+    /// How to choose the row type of each row
+    pub row_filter: crate::crx::RowFilter,
+    #[arg(long)]
+    /// Allow images whose size differs from the original CRX file
+    pub resize: bool,
+    #[arg(long, value_enum, default_value_t)]
+    /// Point of the original image that stays in place when resizing
+    pub anchor: crate::crx::Anchor,
+    #[arg(long, value_enum)]
+    /// Compression codec, defaults to the codec of the original CRX file
+    pub codec: Option<crate::utils::Codec>,
+    #[arg(long, allow_negative_numbers = true)]
+    /// Compression level, defaults to the highest level of the codec
+    pub level: Option<i32>,
+    #[arg(long)]
+    /// Crop fully transparent borders of 32bpp images and move the image offset so the
+    /// sprite stays in place. Images without metadata are treated as a full canvas
+    /// starting at the screen origin. 24bpp images can not be trimmed
+    pub trim: bool,
+    #[arg(long)]
+    /// Composite semi-transparent pixels over this color (#rrggbb) or over the
+    /// original image ("original") when importing into a 24bpp CRX file
+    pub matte: Option<crate::crx::Matte>,
+    #[arg(long, value_enum, default_value_t)]
+    /// What to do with the color of fully transparent pixels when importing into a 32bpp CRX file
+    pub transparent: crate::crx::Transparent,
+}
+
+impl ImportArgs {
+    /// Convert to options used by [crate::crx::Crx]
+    pub fn to_options(&self) -> crate::crx::ImportOptions {
+        crate::crx::ImportOptions {
+            row_filter: self.row_filter,
+            resize: self.resize,
+            anchor: self.anchor,
+            codec: self.codec,
+            level: self.level,
+            trim: self.trim,
+            matte: self.matte,
+            transparent: self.transparent,
+        }
+    }
+}
+
+fn parse_position(s: &str) -> Result<(i32, i32), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or(format!("position must be X,Y, got {}", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<i32>()
+            .map_err(|e| format!("invalid position {}: {}", s, e))
+    };
+    Ok((parse(x)?, parse(y)?))
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (w, h) = s
+        .split_once(['x', 'X'])
+        .ok_or(format!("size must be WxH, got {}", s))?;
+    let parse = |v: &str| match v.trim().parse::<u32>() {
+        Ok(0) => Err(format!("invalid size {}: must not be 0", s)),
+        // Larger images can not be stored in a CRX file.
+        Ok(v) if v > i16::MAX as u32 => {
+            Err(format!("invalid size {}: must be at most {}", s, i16::MAX))
+        }
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("invalid size {}: {}", s, e)),
+    };
+    Ok((parse(w)?, parse(h)?))
+}
+
+fn parse_bpp(s: &str) -> Result<u8, String> {
+    match s {
+        "24" => Ok(24),
+        "32" => Ok(32),
+        _ => Err(format!("bpp must be 24 or 32, got {}", s)),
+    }
+}
+
+/// Row filters usable without an original CRX file.
+fn parse_new_row_filter(s: &str) -> Result<crate::crx::RowFilter, String> {
+    match s {
+        "heuristic" => Ok(crate::crx::RowFilter::Heuristic),
+        "best" => Ok(crate::crx::RowFilter::Best),
+        _ => Err(format!("row filter must be heuristic or best, got {}", s)),
+    }
+}
+
+/// Matte colors, as there is no original image to composite over.
+fn parse_matte_color(s: &str) -> Result<crate::crx::Matte, String> {
+    match s.parse() {
+        Ok(crate::crx::Matte::Original) => {
+            Err("matte must be a color, there is no original image".to_string())
+        }
+        Ok(matte) => Ok(matte),
+        Err(e) => Err(format!("{}", e)),
+    }
 }
 
 impl Arg {
@@ -0,0 +1,145 @@
+use crate::crx::Crx;
+use crate::pck::{NameEncoding, PckReader};
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A CRX image drawn by [compose].
+#[derive(Clone, Debug, Deserialize)]
+pub struct Layer {
+    /// Path to a CRX file, or to a file inside a PCK archive like `chara.pck/face01.crx`.
+    pub file: String,
+    /// Added to the offset stored in the CRX file.
+    #[serde(default)]
+    pub x: i32,
+    /// Added to the offset stored in the CRX file.
+    #[serde(default)]
+    pub y: i32,
+}
+
+impl From<&str> for Layer {
+    fn from(file: &str) -> Self {
+        Layer {
+            file: file.to_owned(),
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+/// A list of layers read from a JSON file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Manifest {
+    /// Canvas size, defaults to the area covered by all layers.
+    #[serde(default)]
+    pub canvas: Option<(u32, u32)>,
+    /// Layers from bottom to top.
+    pub layers: Vec<Layer>,
+}
+
+impl Manifest {
+    /// Reads a manifest, resolving the layer paths relative to its directory.
+    pub fn read_from_file<P: AsRef<Path> + ?Sized>(path: &P) -> Result<Self> {
+        let s = std::fs::read_to_string(path)?;
+        let mut manifest: Self = serde_json::from_str(&s)
+            .map_err(|e| anyhow::anyhow!("Invalid manifest {}: {}", path.as_ref().display(), e))?;
+        if let Some((width, height)) = manifest.canvas {
+            let max = i16::MAX as u32;
+            if width == 0 || height == 0 || width > max || height > max {
+                return Err(anyhow::anyhow!(
+                    "Invalid canvas {}x{} in {}: sides must be between 1 and {}",
+                    width,
+                    height,
+                    path.as_ref().display(),
+                    max
+                ));
+            }
+        }
+        if let Some(dir) = path.as_ref().parent() {
+            for layer in &mut manifest.layers {
+                layer.file = dir.join(&layer.file).to_string_lossy().into_owned();
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+type Archive = PckReader<std::io::Cursor<memmap2::Mmap>>;
+
+/// Returns the PCK file holding `file` and the name inside it, if `file` does not
+/// exist and its parent is a PCK file.
+fn pck_entry(file: &str) -> Option<(&Path, String)> {
+    let path = Path::new(file);
+    if path.is_file() {
+        return None;
+    }
+    let pck = path
+        .parent()
+        .filter(|p| p.is_file() && p.extension().is_some_and(|e| e.eq_ignore_ascii_case("pck")))?;
+    Some((pck, path.file_name()?.to_string_lossy().into_owned()))
+}
+
+/// Reads a CRX file, or a CRX file inside one of the mapped PCK `archives` if
+/// `file` does not exist and its parent is a PCK file.
+fn read_layer<'a>(file: &str, archives: &'a HashMap<PathBuf, Archive>) -> Result<Crx<'a>> {
+    let Some((pck, name)) = pck_entry(file) else {
+        let path = Path::new(file);
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Layer not found: {}", file));
+        }
+        return Crx::read_from_file(path);
+    };
+    let data = archives[pck].get(&name).ok_or(anyhow::anyhow!(
+        "{} not found in {}",
+        name,
+        pck.display()
+    ))?;
+    Crx::read_from_slice(data.into_inner())
+}
+
+/// Draws `layers` from bottom to top at their offsets into a single RGBA image.
+///
+/// With `canvas` the image starts at the screen origin, otherwise it covers all
+/// layers.
+pub fn compose<P: AsRef<Path> + ?Sized>(
+    layers: &[Layer],
+    canvas: Option<(u32, u32)>,
+    encoding: NameEncoding,
+    output: &P,
+) -> Result<()> {
+    if layers.is_empty() {
+        return Err(anyhow::anyhow!("No layers to compose"));
+    }
+    // Map every PCK file once, the images borrow their data from the maps.
+    let mut archives = HashMap::new();
+    for (pck, _) in layers.iter().filter_map(|l| pck_entry(&l.file)) {
+        if !archives.contains_key(pck) {
+            archives.insert(pck.to_path_buf(), PckReader::map_file(pck, encoding)?);
+        }
+    }
+    let images = layers
+        .iter()
+        .map(|l| read_layer(&l.file, &archives))
+        .collect::<Result<Vec<_>>>()?;
+    let (left, top, width, height) = match canvas {
+        Some((width, height)) => (0, 0, width, height),
+        None => {
+            let (mut left, mut top, mut right, mut bottom) =
+                (i32::MAX, i32::MAX, i32::MIN, i32::MIN);
+            for (image, layer) in images.iter().zip(layers) {
+                let (l, t, r, b) = image.bounds();
+                left = left.min(l + layer.x);
+                top = top.min(t + layer.y);
+                right = right.max(r + layer.x);
+                bottom = bottom.max(b + layer.y);
+            }
+            (left, top, (right - left) as u32, (bottom - top) as u32)
+        }
+    };
+    let mut data = vec![0; width as usize * height as usize * 4];
+    for (image, layer) in images.iter().zip(layers) {
+        image.draw_onto(&mut data, left, top, width, height, layer.x, layer.y);
+    }
+    Crx::write_image(output, width, height, 4, &data, None)
+}
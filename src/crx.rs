@@ -1,63 +1,218 @@
-use crate::{ext::*, utils};
+use crate::{
+    ext::*,
+    metadata::{CrxMetadata, MetadataMode, PNG_TEXT_KEYWORD},
+    utils::{self, Codec, ImageFormat},
+};
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::{
-    io::{Read, Seek, Write},
+    borrow::Cow,
+    io::{BufRead, Read, Seek, Write},
     path::Path,
 };
 
 const MAGIC: i32 = 0x47585243; // "CRXG" in ASCII (little-endian)
 
-#[derive(Debug)]
-struct Clip {
+/// An entry of the clip table of a version 3 CRX file.
+///
+/// The meaning of the fields has not been confirmed against the engine, so they
+/// are named after their offset in the entry, which are also the keys in the JSON
+/// files written by `export-clips`. The tool assumes that `field_4`, `field_6`,
+/// `field_8` and `field_a` are a rectangle inside the image when exporting clip
+/// regions and when moving or resizing the image. The other fields are kept as is.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clip {
+    /// Unknown, stored at offset 0x0 of the entry.
     pub field_0: i32,
+    /// Presumably the left edge of the rectangle, stored at offset 0x4.
     pub field_4: i16,
+    /// Presumably the top edge of the rectangle, stored at offset 0x6.
     pub field_6: i16,
+    /// Presumably the width of the rectangle, stored at offset 0x8.
     pub field_8: i16,
+    /// Presumably the height of the rectangle, stored at offset 0xA.
     pub field_a: i16,
+    /// Unknown, stored at offset 0xC of the entry.
     pub field_c: i16,
+    /// Unknown, stored at offset 0xE of the entry.
     pub field_e: i16,
 }
 
-pub struct Crx {
-    inner_x: i16,
-    inner_y: i16,
-    width: i16,
-    height: i16,
-    version: i16,
-    flags: i16,
-    bpp: i16,
-    unknown: i16,
-    data: Vec<u8>,
-    compressed_data: Vec<u8>,
-    clips: Vec<Clip>,
-    encode_type: Vec<u8>,
+impl std::str::FromStr for Clip {
+    type Err = anyhow::Error;
+
+    /// Parses a clip from its fields in order, `field_0,field_4,...,field_e`.
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<i32>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if values.len() != 7 {
+            return Err(anyhow::anyhow!(
+                "Clip requires 7 comma separated values, got {}",
+                values.len()
+            ));
+        }
+        let field = |i: usize| {
+            i16::try_from(values[i])
+                .map_err(|_| anyhow::anyhow!("Clip value out of range: {}", values[i]))
+        };
+        Ok(Clip {
+            field_0: values[0],
+            field_4: field(1)?,
+            field_6: field(2)?,
+            field_8: field(3)?,
+            field_a: field(4)?,
+            field_c: field(5)?,
+            field_e: field(6)?,
+        })
+    }
 }
 
-impl std::fmt::Debug for Crx {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Crx")
-            .field("inner_x", &self.inner_x)
-            .field("inner_y", &self.inner_y)
-            .field("width", &self.width)
-            .field("height", &self.height)
-            .field("version", &self.version)
-            .field("flags", &self.flags)
-            .field("bpp", &self.bpp)
-            .field("unknown", &self.unknown)
-            .field("data_size", &self.data.len())
-            .field("compressed_data_size", &self.compressed_data.len())
-            .field("clips", &self.clips)
-            .finish()
+/// How row types are chosen when encoding an image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RowFilter {
+    /// Reuse the row types of the original image.
+    #[default]
+    Original,
+    /// Pick the row type with the smallest residuals.
+    Heuristic,
+    /// Try every row type and keep the one that compresses best.
+    ///
+    /// Every row is compressed on its own with the output codec at a fast level
+    /// (zstd 3 or zlib 1), so this is much slower than the other filters and only
+    /// approximates how the rows compress in the final stream. The heuristic row
+    /// types are used instead when they compress smaller.
+    Best,
+}
+
+/// The point of an image that stays in place when its size changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Returns how far the anchor point moves when the image grows by `dw`x`dh`.
+    fn offset(&self, dw: i32, dh: i32) -> (i32, i32) {
+        let (fx, fy) = match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (1, 0),
+            Anchor::TopRight => (2, 0),
+            Anchor::Left => (0, 1),
+            Anchor::Center => (1, 1),
+            Anchor::Right => (2, 1),
+            Anchor::BottomLeft => (0, 2),
+            Anchor::Bottom => (1, 2),
+            Anchor::BottomRight => (2, 2),
+        };
+        (dw * fx / 2, dh * fy / 2)
+    }
+}
+
+/// What semi-transparent pixels are composited over when alpha is dropped for 24bpp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matte {
+    /// A solid color.
+    Color([u8; 3]),
+    /// The pixels of the original CRX image.
+    Original,
+}
+
+impl std::str::FromStr for Matte {
+    type Err = anyhow::Error;
+
+    /// Parses `original` or a color in `rrggbb` / `#rrggbb` form.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("original") {
+            return Ok(Matte::Original);
+        }
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(anyhow::anyhow!(
+                "Matte must be \"original\" or a color like #rrggbb, got {}",
+                s
+            ));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+        Ok(Matte::Color([channel(0)?, channel(2)?, channel(4)?]))
+    }
+}
+
+/// What happens to the color of fully transparent pixels in 32bpp images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Transparent {
+    /// Keep the color as it is in the source image.
+    #[default]
+    Preserve,
+    /// Set the color to black, which compresses better.
+    Zero,
+    /// Fill the color from the nearest visible pixels to avoid dark fringes when filtered.
+    Bleed,
+}
+
+/// Options used when converting an image into CRX data.
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    pub row_filter: RowFilter,
+    /// Accept images whose size differs from the original CRX.
+    pub resize: bool,
+    /// Which point of the original image stays in place on screen when resizing.
+    pub anchor: Anchor,
+    /// Codec used to compress the image data. Defaults to the codec of the original image.
+    pub codec: Option<Codec>,
+    /// Compression level. Defaults to the highest level of the codec.
+    pub level: Option<i32>,
+    /// Crop fully transparent borders, moving the image offset to keep it in place.
+    pub trim: bool,
+    /// Background used for semi-transparent pixels when importing into a 24bpp image.
+    /// Alpha is simply dropped when unset.
+    pub matte: Option<Matte>,
+    /// Color policy for fully transparent pixels when importing into a 32bpp image.
+    pub transparent: Transparent,
+}
+
+impl ImportOptions {
+    /// Whether these options ask for an encoding different from the original data.
+    fn changes_encoding(&self, codec: Codec) -> bool {
+        self.row_filter != RowFilter::Original
+            || self.level.is_some()
+            || self.codec.is_some_and(|c| c != codec)
     }
 }
 
-impl Crx {
+/// The header of a CRX file, parsed without reading the image data.
+#[derive(Clone, Debug)]
+pub struct CrxHeader {
+    pub inner_x: i16,
+    pub inner_y: i16,
+    pub width: i16,
+    pub height: i16,
+    pub version: i16,
+    pub flags: i16,
+    pub bpp: i16,
+    pub unknown: i16,
+    pub clips: Vec<Clip>,
+    pub compressed_size: u32,
+}
+
+impl CrxHeader {
     pub fn read_from_file<F: AsRef<Path> + ?Sized>(filename: &F) -> Result<Self> {
         let file = std::fs::File::open(filename)?;
         let mut file = std::io::BufReader::new(file);
         Self::read_from(&mut file, || Ok(std::fs::metadata(filename)?.len()))
     }
 
+    /// Reads the header and leaves `file` at the start of the compressed data.
     pub fn read_from<R, T>(file: &mut R, stream_len: T) -> Result<Self>
     where
         R: Read + Seek,
@@ -84,56 +239,396 @@ impl Crx {
         if bpp != 0 && bpp != 1 {
             return Err(anyhow::anyhow!("Unsupported CRX bpp: {}", bpp));
         }
+        if width <= 0 || height <= 0 {
+            return Err(anyhow::anyhow!(
+                "Invalid CRX image size: {}x{}",
+                width,
+                height
+            ));
+        }
         let mut clips = Vec::new();
         if version >= 3 {
             let clip_count = file.read_i32()?;
+            if clip_count < 0 {
+                return Err(anyhow::anyhow!("Invalid CRX clip count: {}", clip_count));
+            }
             for _ in 0..clip_count {
-                let field_0 = file.read_i32()?;
-                let field_4 = file.read_i16()?;
-                let field_6 = file.read_i16()?;
-                let field_8 = file.read_i16()?;
-                let field_a = file.read_i16()?;
-                let field_c = file.read_i16()?;
-                let field_e = file.read_i16()?;
                 clips.push(Clip {
-                    field_0,
-                    field_4,
-                    field_6,
-                    field_8,
-                    field_a,
-                    field_c,
-                    field_e,
+                    field_0: file.read_i32()?,
+                    field_4: file.read_i16()?,
+                    field_6: file.read_i16()?,
+                    field_8: file.read_i16()?,
+                    field_a: file.read_i16()?,
+                    field_c: file.read_i16()?,
+                    field_e: file.read_i16()?,
                 });
             }
         }
         let comp_size = if (flags & 0x10) == 0 {
             let size = stream_len()?;
-            (size - file.stream_position()?) as u32
+            let pos = file.stream_position()?;
+            size.checked_sub(pos).ok_or(anyhow::anyhow!(
+                "CRX header ends at {} beyond the stream length {}",
+                pos,
+                size
+            ))? as u32
         } else {
             file.read_i32()? as u32
         };
-        let mut compressed_data = Vec::with_capacity(comp_size as usize);
-        compressed_data.resize(comp_size as usize, 0);
-        file.read_exact(&mut compressed_data)?;
-        let adata = if compressed_data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
-            crate::utils::decompress_data(&compressed_data)?
-        } else {
-            fdeflate::decompress_to_vec(&compressed_data)
-                .map_err(|e| anyhow::anyhow!("Failed to decompress CRX data: {:?}", e))?
-        };
-        let pixel_size = if bpp == 0 { 3 } else { 4 };
-        let size = width as usize * height as usize * pixel_size as usize;
-        let mut data = Vec::with_capacity(size);
-        data.resize(size, 0);
-        let mut encode_type = Vec::with_capacity(height as usize);
-        Self::decode_image(
-            &mut data,
-            &adata,
+        Ok(CrxHeader {
+            inner_x,
+            inner_y,
             width,
             height,
-            pixel_size,
-            &mut encode_type,
+            version,
+            flags,
+            bpp,
+            unknown,
+            clips,
+            compressed_size: comp_size,
+        })
+    }
+
+    /// Reads the header from `data` and returns it with the compressed data.
+    pub fn split_slice(data: &[u8]) -> Result<(Self, &[u8])> {
+        let mut cursor = std::io::Cursor::new(data);
+        let header = Self::read_from(&mut cursor, || Ok(data.len() as u64))?;
+        let start = cursor.position() as usize;
+        let rest = &data[start..];
+        let compressed_data =
+            rest.get(..header.compressed_size as usize)
+                .ok_or(anyhow::anyhow!(
+                    "Truncated CRX data: expected {} compressed bytes, got {}",
+                    header.compressed_size,
+                    rest.len()
+                ))?;
+        Ok((header, compressed_data))
+    }
+}
+
+impl CrxHeader {
+    /// Returns the header fields that are not stored in the pixels.
+    pub fn metadata(&self) -> CrxMetadata {
+        CrxMetadata {
+            inner_x: self.inner_x,
+            inner_y: self.inner_y,
+            version: self.version,
+            flags: self.flags,
+            unknown: self.unknown,
+            clips: self.clips.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for CrxHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x{} at ({}, {}), version {}, flags 0x{:02X}, {} bpp, unknown {}, {} compressed bytes",
+            self.width,
+            self.height,
+            self.inner_x,
+            self.inner_y,
+            self.version,
+            self.flags,
+            if self.bpp == 0 { 24 } else { 32 },
+            self.unknown,
+            self.compressed_size
         )?;
+        for clip in &self.clips {
+            write!(f, "\n  clip: {:?}", clip)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads decompressed image data, keeping track of the offset for error messages.
+struct RowReader<R: BufRead> {
+    inner: R,
+    offset: usize,
+}
+
+impl<R: BufRead> RowReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.inner
+            .fill_buf()
+            .map_err(|e| anyhow::anyhow!("Failed to decompress CRX data: {}", e))
+    }
+
+    /// Returns the next byte without consuming it.
+    fn peek_byte(&mut self, y: i16) -> Result<u8> {
+        let offset = self.offset;
+        self.fill_buf()?.first().copied().ok_or(anyhow::anyhow!(
+            "Unexpected end of CRX data in row {} at byte offset {}",
+            y,
+            offset
+        ))
+    }
+
+    fn read_byte(&mut self, y: i16) -> Result<u8> {
+        let value = self.peek_byte(y)?;
+        self.inner.consume(1);
+        self.offset += 1;
+        Ok(value)
+    }
+
+    /// Fills `buf` completely.
+    fn read_row(&mut self, buf: &mut [u8], y: i16) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let available = self.fill_buf()?;
+            if available.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Unexpected end of CRX data in row {} at byte offset {}: need {} bytes, {} available",
+                    y,
+                    self.offset,
+                    buf.len(),
+                    filled
+                ));
+            }
+            let n = available.len().min(buf.len() - filled);
+            buf[filled..filled + n].copy_from_slice(&available[..n]);
+            self.inner.consume(n);
+            filled += n;
+        }
+        self.offset += filled;
+        Ok(())
+    }
+}
+
+/// Decodes a CRX image one row at a time while decompressing its data, so that the
+/// whole image never has to be held in memory.
+pub struct CrxRowDecoder<'a> {
+    header: CrxHeader,
+    codec: Codec,
+    reader: RowReader<Box<dyn BufRead + 'a>>,
+    pixel_size: usize,
+    y: i16,
+    /// Encoded bytes of the current row.
+    src: Vec<u8>,
+    /// Previous and current rows in the channel order of the CRX data.
+    prev: Vec<u8>,
+    cur: Vec<u8>,
+    /// Current row as RGB or RGBA.
+    row: Vec<u8>,
+    row_types: Vec<u8>,
+}
+
+impl<'a> CrxRowDecoder<'a> {
+    /// Reads the header from `file` and prepares to decode the rows that follow it.
+    pub fn new<R, T>(file: &'a mut R, stream_len: T) -> Result<Self>
+    where
+        R: Read + Seek,
+        T: FnOnce() -> Result<u64>,
+    {
+        let header = CrxHeader::read_from(file, stream_len)?;
+        let size = header.compressed_size as u64;
+        Self::with_header(header, std::io::BufReader::new(file.take(size)))
+    }
+
+    /// Reads the header from `data` and decodes the rows straight from the slice.
+    pub fn from_slice(data: &'a [u8]) -> Result<Self> {
+        let (header, compressed_data) = CrxHeader::split_slice(data)?;
+        Self::with_header(header, compressed_data)
+    }
+
+    /// Decodes the rows from `src`, the compressed data following `header`.
+    fn with_header<S: BufRead + 'a>(header: CrxHeader, mut src: S) -> Result<Self> {
+        let codec = Codec::detect(src.fill_buf()?);
+        let data: Box<dyn BufRead + 'a> = match codec {
+            Codec::Zstd => Box::new(std::io::BufReader::new(
+                zstd::stream::read::Decoder::with_buffer(src)?,
+            )),
+            Codec::Zlib => Box::new(std::io::BufReader::new(flate2::bufread::ZlibDecoder::new(
+                src,
+            ))),
+        };
+        let pixel_size = if header.bpp == 0 { 3 } else { 4 };
+        let row_size = header.width as usize * pixel_size;
+        Ok(CrxRowDecoder {
+            codec,
+            reader: RowReader {
+                inner: data,
+                offset: 0,
+            },
+            pixel_size,
+            y: 0,
+            src: vec![0; row_size],
+            prev: vec![0; row_size],
+            cur: vec![0; row_size],
+            row: vec![0; row_size],
+            row_types: Vec::with_capacity(header.height as usize),
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &CrxHeader {
+        &self.header
+    }
+
+    /// Decodes the next row and returns its pixels as RGB for 24bpp images or RGBA for
+    /// 32bpp images, or `None` after the last row.
+    pub fn next_row(&mut self) -> Result<Option<&[u8]>> {
+        let y = self.y;
+        if y >= self.header.height {
+            return Ok(None);
+        }
+        // Row 0 has no previous row: row types 1 and 3 read the zeroed initial buffer
+        // and row type 2 predicts from the left pixel of the current row, like type 0.
+        std::mem::swap(&mut self.prev, &mut self.cur);
+        let row_type = self.reader.read_byte(y)?;
+        let ps = self.pixel_size;
+        match row_type {
+            0..=3 => {
+                self.reader.read_row(&mut self.src, y)?;
+                match row_type {
+                    0 => Crx::decode_row0(&mut self.cur, &self.src, ps),
+                    2 if y == 0 => Crx::decode_row0(&mut self.cur, &self.src, ps),
+                    1 => Crx::decode_row1(&mut self.cur, &self.src, &self.prev),
+                    2 => Crx::decode_row2(&mut self.cur, &self.src, &self.prev, ps),
+                    _ => Crx::decode_row3(&mut self.cur, &self.src, &self.prev, ps),
+                }
+            }
+            4 => Crx::decode_row4(&mut self.cur, &mut self.reader, ps, y)?,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Invalid row type {} in row {} at byte offset {}",
+                    row_type,
+                    y,
+                    self.reader.offset - 1
+                ));
+            }
+        }
+        self.row_types.push(row_type);
+        Crx::convert_row(&mut self.row, &self.cur, ps);
+        self.y += 1;
+        Ok(Some(&self.row))
+    }
+
+    /// Calls `f` with the index and pixels of every remaining row.
+    pub fn for_each_row<F: FnMut(i16, &[u8]) -> Result<()>>(&mut self, mut f: F) -> Result<()> {
+        loop {
+            let y = self.y;
+            match self.next_row()? {
+                Some(row) => f(y, row)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Decodes the image straight into a PNG file, one row at a time.
+    pub fn write_png<F: AsRef<Path> + ?Sized>(
+        &mut self,
+        filename: &F,
+        metadata: MetadataMode,
+    ) -> Result<()> {
+        let text = Crx::metadata_text(filename, &self.header.metadata(), metadata)?;
+        let (width, height) = (self.header.width as u32, self.header.height as u32);
+        let mut writer = Crx::png_writer(filename, width, height, self.pixel_size, text)?;
+        let mut stream = writer.stream_writer_with_size(1 << 16)?;
+        self.for_each_row(|_, row| Ok(stream.write_all(row)?))?;
+        stream.finish()?;
+        Ok(())
+    }
+}
+
+pub struct Crx<'a> {
+    inner_x: i16,
+    inner_y: i16,
+    width: i16,
+    height: i16,
+    version: i16,
+    flags: i16,
+    bpp: i16,
+    unknown: i16,
+    data: Vec<u8>,
+    /// Borrowed from the input when read with [Crx::read_from_slice], until the
+    /// image is encoded again.
+    compressed_data: Cow<'a, [u8]>,
+    clips: Vec<Clip>,
+    encode_type: Vec<u8>,
+    codec: Codec,
+    /// Whether `compressed_data` was produced by this tool instead of read from a file.
+    reencoded: bool,
+}
+
+impl std::fmt::Debug for Crx<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Crx")
+            .field("inner_x", &self.inner_x)
+            .field("inner_y", &self.inner_y)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("version", &self.version)
+            .field("flags", &self.flags)
+            .field("bpp", &self.bpp)
+            .field("unknown", &self.unknown)
+            .field("data_size", &self.data.len())
+            .field("compressed_data_size", &self.compressed_data.len())
+            .field("codec", &self.codec)
+            .field("clips", &self.clips)
+            .finish()
+    }
+}
+
+impl<'a> Crx<'a> {
+    pub fn read_from_file<F: AsRef<Path> + ?Sized>(filename: &F) -> Result<Self> {
+        let file = std::fs::File::open(filename)?;
+        let mut file = std::io::BufReader::new(file);
+        Self::read_from(&mut file, || Ok(std::fs::metadata(filename)?.len()))
+    }
+
+    pub fn read_from<R, T>(file: &mut R, stream_len: T) -> Result<Self>
+    where
+        R: Read + Seek,
+        T: FnOnce() -> Result<u64>,
+    {
+        let header = CrxHeader::read_from(file, stream_len)?;
+        let mut compressed_data = Vec::new();
+        file.take(header.compressed_size as u64)
+            .read_to_end(&mut compressed_data)?;
+        if compressed_data.len() != header.compressed_size as usize {
+            return Err(anyhow::anyhow!(
+                "Truncated CRX data: expected {} compressed bytes, got {}",
+                header.compressed_size,
+                compressed_data.len()
+            ));
+        }
+        Self::decode(header, Cow::Owned(compressed_data))
+    }
+
+    /// Reads a CRX file held in memory, like one inside a memory-mapped PCK file.
+    ///
+    /// The compressed data is borrowed from `data`, so writing the image unchanged
+    /// does not copy it.
+    pub fn read_from_slice(data: &'a [u8]) -> Result<Self> {
+        let (header, compressed_data) = CrxHeader::split_slice(data)?;
+        Self::decode(header, Cow::Borrowed(compressed_data))
+    }
+
+    fn decode(header: CrxHeader, compressed_data: Cow<'a, [u8]>) -> Result<Self> {
+        let CrxHeader {
+            inner_x,
+            inner_y,
+            width,
+            height,
+            version,
+            flags,
+            bpp,
+            unknown,
+            ref clips,
+            compressed_size: _,
+        } = header;
+        let clips = clips.clone();
+        let pixel_size = if bpp == 0 { 3 } else { 4 };
+        let mut data = Vec::with_capacity(width as usize * height as usize * pixel_size);
+        let (codec, encode_type) = {
+            let mut decoder = CrxRowDecoder::with_header(header, &*compressed_data)?;
+            while let Some(row) = decoder.next_row()? {
+                data.extend_from_slice(row);
+            }
+            (decoder.codec, decoder.row_types)
+        };
         let crx = Crx {
             inner_x,
             inner_y,
@@ -147,82 +642,1053 @@ impl Crx {
             compressed_data,
             clips,
             encode_type,
+            codec,
+            reencoded: false,
         };
         eprintln!("Image metadata: {:?}", crx);
         Ok(crx)
     }
 
-    pub fn export_png<F: AsRef<Path> + ?Sized>(&self, filename: &F) -> Result<()> {
+    /// Exports the image in the format given by the extension of `filename`.
+    ///
+    /// Only PNG can embed metadata, other formats fall back to a sidecar file.
+    pub fn export_image<F: AsRef<Path> + ?Sized>(
+        &self,
+        filename: &F,
+        metadata: MetadataMode,
+    ) -> Result<()> {
+        let text = Self::metadata_text(filename, &self.metadata(), metadata)?;
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        Self::write_image(
+            filename,
+            self.width as u32,
+            self.height as u32,
+            pixel_size,
+            &self.data,
+            text,
+        )
+    }
+
+    /// Exports the image placed at `inner_x`/`inner_y` on a canvas that starts at the
+    /// screen origin, undoing the trimming of sprites.
+    ///
+    /// The canvas is grown to the left or top when the offset is negative. The
+    /// metadata stores the position of the canvas, so the image can be trimmed
+    /// again on import.
+    pub fn export_untrimmed<F: AsRef<Path> + ?Sized>(
+        &self,
+        filename: &F,
+        metadata: MetadataMode,
+    ) -> Result<()> {
+        let (x, y) = (self.inner_x as i32, self.inner_y as i32);
+        let (left, top) = (x.min(0), y.min(0));
+        let right = (x + self.width as i32).max(0);
+        let bottom = (y + self.height as i32).max(0);
+        let (width, height) = ((right - left) as u32, (bottom - top) as u32);
+        let (data, canvas_metadata) = self.place_on_canvas(left, top, width, height)?;
+        let text = Self::metadata_text(filename, &canvas_metadata, metadata)?;
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        Self::write_image(filename, width, height, pixel_size, &data, text)
+    }
+
+    /// Exports the image placed at `inner_x`/`inner_y` on a `width`x`height` canvas
+    /// that starts at the screen origin, the way the engine draws it.
+    pub fn export_canvas<F: AsRef<Path> + ?Sized>(
+        &self,
+        filename: &F,
+        width: u32,
+        height: u32,
+        metadata: MetadataMode,
+    ) -> Result<()> {
+        let (x, y) = (self.inner_x as i64, self.inner_y as i64);
+        if x < 0
+            || y < 0
+            || x + self.width as i64 > width as i64
+            || y + self.height as i64 > height as i64
+        {
+            eprintln!(
+                "Warning: {}x{} image at ({}, {}) does not fit on the {}x{} canvas and is cut off.",
+                self.width, self.height, x, y, width, height
+            );
+        }
+        let (data, canvas_metadata) = self.place_on_canvas(0, 0, width, height)?;
+        let text = Self::metadata_text(filename, &canvas_metadata, metadata)?;
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        Self::write_image(filename, width, height, pixel_size, &data, text)
+    }
+
+    /// Draws the image at `inner_x`/`inner_y` onto an empty `width`x`height` canvas
+    /// whose top left corner is at `left`,`top` on screen. Returns the pixels of the
+    /// canvas and metadata describing the canvas as the image.
+    fn place_on_canvas(
+        &self,
+        left: i32,
+        top: i32,
+        width: u32,
+        height: u32,
+    ) -> Result<(Vec<u8>, CrxMetadata)> {
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        let (x, y) = (self.inner_x as i32 - left, self.inner_y as i32 - top);
+        let mut data = vec![0; width as usize * height as usize * pixel_size];
+        let src_stride = self.width as usize * pixel_size;
+        let dst_stride = width as usize * pixel_size;
+        let x0 = x.max(0);
+        let x1 = (x + self.width as i32).min(width as i32);
+        if x0 < x1 {
+            for row in y.max(0)..(y + self.height as i32).min(height as i32) {
+                let src = (row - y) as usize * src_stride + (x0 - x) as usize * pixel_size;
+                let dst = row as usize * dst_stride + x0 as usize * pixel_size;
+                let len = (x1 - x0) as usize * pixel_size;
+                data[dst..dst + len].copy_from_slice(&self.data[src..src + len]);
+            }
+        }
+        let mut metadata = self.metadata();
+        metadata.inner_x = i16::try_from(left)
+            .map_err(|_| anyhow::anyhow!("Canvas position out of range: {}", left))?;
+        metadata.inner_y = i16::try_from(top)
+            .map_err(|_| anyhow::anyhow!("Canvas position out of range: {}", top))?;
+        for clip in &mut metadata.clips {
+            clip.field_4 = i16::try_from(clip.field_4 as i32 + x)
+                .map_err(|_| anyhow::anyhow!("Clip {:?} is out of range on the canvas", clip))?;
+            clip.field_6 = i16::try_from(clip.field_6 as i32 + y)
+                .map_err(|_| anyhow::anyhow!("Clip {:?} is out of range on the canvas", clip))?;
+        }
+        Ok((data, metadata))
+    }
+
+    /// Exports a 32bpp image as a color image and a grayscale alpha mask.
+    ///
+    /// Metadata is stored with the color image.
+    pub fn export_image_with_mask<F: AsRef<Path> + ?Sized, M: AsRef<Path> + ?Sized>(
+        &self,
+        filename: &F,
+        mask: &M,
+        metadata: MetadataMode,
+    ) -> Result<()> {
+        if self.bpp != 1 {
+            return Err(anyhow::anyhow!("Only 32bpp CRX images have an alpha mask"));
+        }
+        // Fail before any file is written.
+        for path in [filename.as_ref(), mask.as_ref()] {
+            if ImageFormat::from_path(path).is_none() {
+                return Err(anyhow::anyhow!(
+                    "Unsupported image format: {}",
+                    path.display()
+                ));
+            }
+        }
+        let text = Self::metadata_text(filename, &self.metadata(), metadata)?;
+        let (width, height) = (self.width as u32, self.height as u32);
+        let color = Self::rgba_to_rgb(&self.data);
+        Self::write_image(filename, width, height, 3, &color, text)?;
+        let alpha: Vec<u8> = self.data.chunks_exact(4).map(|p| p[3]).collect();
+        Self::write_image(mask, width, height, 1, &alpha, None)
+    }
+
+    /// Stores `metadata` as `mode` asks, returning the text to embed into `filename`.
+    fn metadata_text<F: AsRef<Path> + ?Sized>(
+        filename: &F,
+        metadata: &CrxMetadata,
+        mode: MetadataMode,
+    ) -> Result<Option<String>> {
+        let mode = if mode == MetadataMode::Embed
+            && ImageFormat::from_path(filename) != Some(ImageFormat::Png)
+        {
+            eprintln!(
+                "{} can not embed metadata, writing it to a sidecar file instead.",
+                filename.as_ref().display()
+            );
+            MetadataMode::Sidecar
+        } else {
+            mode
+        };
+        Ok(match mode {
+            MetadataMode::Embed => Some(metadata.to_json()?),
+            MetadataMode::Sidecar => {
+                metadata.write_sidecar(filename)?;
+                None
+            }
+            MetadataMode::Skip => None,
+        })
+    }
+
+    /// Writes 8-bit gray, RGB or RGBA pixels (`channels` 1, 3 or 4) in the format given
+    /// by the extension of `filename`. `metadata` is only stored in PNG files. Gray
+    /// pixels are written as RGB to formats without grayscale.
+    pub(crate) fn write_image<F: AsRef<Path> + ?Sized>(
+        filename: &F,
+        width: u32,
+        height: u32,
+        channels: usize,
+        data: &[u8],
+        metadata: Option<String>,
+    ) -> Result<()> {
+        let format = ImageFormat::from_path(filename).ok_or(anyhow::anyhow!(
+            "Unsupported image format: {}",
+            filename.as_ref().display()
+        ))?;
+        let image_format = match format {
+            ImageFormat::Png => {
+                return Self::write_png(filename, width, height, channels, data, metadata);
+            }
+            ImageFormat::Tga => image::ImageFormat::Tga,
+            ImageFormat::Bmp => image::ImageFormat::Bmp,
+            ImageFormat::Qoi => image::ImageFormat::Qoi,
+            ImageFormat::Webp => image::ImageFormat::WebP,
+        };
+        let rgb;
+        let (channels, data) = if channels == 1 && !format.has_grayscale() {
+            rgb = data.iter().flat_map(|&v| [v, v, v]).collect::<Vec<_>>();
+            (3, rgb.as_slice())
+        } else {
+            (channels, data)
+        };
+        let color = match channels {
+            1 => image::ExtendedColorType::L8,
+            3 => image::ExtendedColorType::Rgb8,
+            _ => image::ExtendedColorType::Rgba8,
+        };
+        image::save_buffer_with_format(filename, data, width, height, color, image_format)?;
+        Ok(())
+    }
+
+    /// Writes 8-bit gray, RGB or RGBA pixels to a PNG file, with optional metadata.
+    fn write_png<F: AsRef<Path> + ?Sized>(
+        filename: &F,
+        width: u32,
+        height: u32,
+        channels: usize,
+        data: &[u8],
+        metadata: Option<String>,
+    ) -> Result<()> {
+        let mut writer = Self::png_writer(filename, width, height, channels, metadata)?;
+        writer.write_image_data(data)?;
+        Ok(())
+    }
+
+    /// Creates a PNG file for 8-bit gray, RGB or RGBA pixels and writes its header.
+    fn png_writer<F: AsRef<Path> + ?Sized>(
+        filename: &F,
+        width: u32,
+        height: u32,
+        channels: usize,
+        metadata: Option<String>,
+    ) -> Result<png::Writer<std::io::BufWriter<std::fs::File>>> {
         let f = std::fs::File::create(filename)?;
         let f = std::io::BufWriter::new(f);
-        let mut encoder = png::Encoder::new(f, self.width as u32, self.height as u32);
-        encoder.set_color(if self.bpp == 0 {
-            png::ColorType::Rgb
-        } else {
-            png::ColorType::Rgba
+        let mut encoder = png::Encoder::new(f, width, height);
+        encoder.set_color(match channels {
+            1 => png::ColorType::Grayscale,
+            3 => png::ColorType::Rgb,
+            _ => png::ColorType::Rgba,
         });
         encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header()?;
-        writer.write_image_data(&self.data)?;
+        if let Some(text) = metadata {
+            encoder.add_text_chunk(PNG_TEXT_KEYWORD.to_owned(), text)?;
+        }
+        Ok(encoder.write_header()?)
+    }
+
+    /// Draws the image at `inner_x`/`inner_y` moved by `dx`,`dy` over `canvas`, an RGBA
+    /// image of `width`x`height` pixels whose top left corner is at `left`,`top` on
+    /// screen. Parts outside of the canvas are cut off.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_onto(
+        &self,
+        canvas: &mut [u8],
+        left: i32,
+        top: i32,
+        width: u32,
+        height: u32,
+        dx: i32,
+        dy: i32,
+    ) {
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        let x = self.inner_x as i32 + dx - left;
+        let y = self.inner_y as i32 + dy - top;
+        let x0 = x.max(0);
+        let x1 = (x + self.width as i32).min(width as i32);
+        if x0 >= x1 {
+            return;
+        }
+        let src_stride = self.width as usize * pixel_size;
+        let dst_stride = width as usize * 4;
+        for row in y.max(0)..(y + self.height as i32).min(height as i32) {
+            let src = (row - y) as usize * src_stride + (x0 - x) as usize * pixel_size;
+            let src = &self.data[src..src + (x1 - x0) as usize * pixel_size];
+            let src = if self.bpp == 0 {
+                Self::rgb_to_rgba(src)
+            } else {
+                src.to_vec()
+            };
+            let dst = row as usize * dst_stride + x0 as usize * 4;
+            let dst = &mut canvas[dst..dst + (x1 - x0) as usize * 4];
+            let blended = Self::blend_rgba(&src, dst, 4);
+            dst.copy_from_slice(&blended);
+        }
+    }
+
+    /// Returns the area covered by the image on screen as `(left, top, right, bottom)`.
+    pub fn bounds(&self) -> (i32, i32, i32, i32) {
+        let (x, y) = (self.inner_x as i32, self.inner_y as i32);
+        (x, y, x + self.width as i32, y + self.height as i32)
+    }
+
+    pub fn clips(&self) -> &[Clip] {
+        &self.clips
+    }
+
+    /// Replaces the clip table, upgrading the image to version 3 if needed.
+    pub fn set_clips(&mut self, clips: Vec<Clip>) {
+        if !clips.is_empty() && self.version < 3 {
+            eprintln!(
+                "Clips require CRX version 3, upgrading from version {}.",
+                self.version
+            );
+            self.version = 3;
+        }
+        for clip in &clips {
+            if clip.field_4 < 0
+                || clip.field_6 < 0
+                || clip.field_8 < 0
+                || clip.field_a < 0
+                || clip.field_4 as i32 + clip.field_8 as i32 > self.width as i32
+                || clip.field_6 as i32 + clip.field_a as i32 > self.height as i32
+            {
+                eprintln!("Clip {:?} is outside of the image bounds.", clip);
+            }
+        }
+        self.clips = clips;
+    }
+
+    /// Exports the rectangle of a clip, `field_4` to `field_a`, as a PNG file.
+    pub fn export_clip_png<F: AsRef<Path> + ?Sized>(
+        &self,
+        clip: &Clip,
+        filename: &F,
+    ) -> Result<()> {
+        let x = (clip.field_4.max(0) as usize).min(self.width as usize);
+        let y = (clip.field_6.max(0) as usize).min(self.height as usize);
+        let width =
+            (clip.field_4 as i32 + clip.field_8 as i32).clamp(0, self.width as i32) as usize;
+        let height =
+            (clip.field_6 as i32 + clip.field_a as i32).clamp(0, self.height as i32) as usize;
+        if width <= x || height <= y {
+            return Err(anyhow::anyhow!("Clip {:?} is empty inside the image", clip));
+        }
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        let stride = self.width as usize * pixel_size;
+        let mut data = Vec::with_capacity((width - x) * (height - y) * pixel_size);
+        for row in y..height {
+            let start = row * stride + x * pixel_size;
+            data.extend_from_slice(&self.data[start..row * stride + width * pixel_size]);
+        }
+        Self::write_image(
+            filename,
+            (width - x) as u32,
+            (height - y) as u32,
+            pixel_size,
+            &data,
+            None,
+        )
+    }
+
+    /// Returns the header fields that are not stored in the pixels.
+    pub fn metadata(&self) -> CrxMetadata {
+        CrxMetadata {
+            inner_x: self.inner_x,
+            inner_y: self.inner_y,
+            version: self.version,
+            flags: self.flags,
+            unknown: self.unknown,
+            clips: self.clips.clone(),
+        }
+    }
+
+    pub fn apply_metadata(&mut self, metadata: &CrxMetadata) -> Result<()> {
+        metadata.validate()?;
+        self.inner_x = metadata.inner_x;
+        self.inner_y = metadata.inner_y;
+        self.version = metadata.version;
+        self.flags = metadata.flags;
+        self.unknown = metadata.unknown;
+        self.clips = metadata.clips.clone();
         Ok(())
     }
 
-    pub fn import_png<F: AsRef<Path> + ?Sized>(&mut self, filename: &F) -> Result<()> {
-        let f = std::fs::File::open(filename)?;
-        let mut decoder = png::Decoder::new(f);
-        let info = decoder.read_header_info()?;
-        if info.width != self.width as u32 {
+    /// Creates a new CRX image from an image file without an original CRX.
+    ///
+    /// The data is compressed with zlib unless `options` asks for another codec, as
+    /// that is what every engine version can read.
+    pub fn from_image<F: AsRef<Path> + ?Sized>(
+        filename: &F,
+        bpp: i16,
+        metadata: &CrxMetadata,
+        options: &ImportOptions,
+    ) -> Result<Self> {
+        metadata.validate()?;
+        if bpp != 0 && bpp != 1 {
+            return Err(anyhow::anyhow!("Unsupported CRX bpp: {}", bpp));
+        }
+        let (width, height, data) = Self::read_image(filename)?;
+        let data = Self::convert_rgba(data, width, bpp, options, None)?;
+        let (width, height) = Self::checked_size(width, height)?;
+        let pixel_size = if bpp == 0 { 3 } else { 4 };
+        let encode_type = Self::generate_encode_type(&data, width, height, pixel_size)?;
+        let mut crx = Crx {
+            inner_x: metadata.inner_x,
+            inner_y: metadata.inner_y,
+            width,
+            height,
+            version: metadata.version,
+            flags: metadata.flags,
+            bpp,
+            unknown: metadata.unknown,
+            data,
+            compressed_data: Cow::Borrowed(&[]),
+            clips: metadata.clips.clone(),
+            encode_type,
+            codec: Codec::Zlib,
+            reencoded: false,
+        };
+        if options.trim {
+            crx.trim()?;
+        }
+        crx.encode(options)?;
+        Ok(crx)
+    }
+
+    pub fn import_image<F: AsRef<Path> + ?Sized>(
+        &mut self,
+        filename: &F,
+        options: &ImportOptions,
+    ) -> Result<()> {
+        let (width, height, data) = Self::read_image(filename)?;
+        self.import_rgba(filename, width, height, data, options)
+    }
+
+    /// Imports a color image and a grayscale alpha mask of the same size.
+    ///
+    /// The alpha of the color image is replaced by the mask, so this only works for
+    /// 32bpp images. Metadata is read from the color image.
+    pub fn import_image_with_mask<F: AsRef<Path> + ?Sized, M: AsRef<Path> + ?Sized>(
+        &mut self,
+        filename: &F,
+        mask: &M,
+        options: &ImportOptions,
+    ) -> Result<()> {
+        if self.bpp != 1 {
+            return Err(anyhow::anyhow!("Only 32bpp CRX images have an alpha mask"));
+        }
+        let (width, height, mut data) = Self::read_image(filename)?;
+        let (mask_width, mask_height, alpha) = Self::read_image(mask)?;
+        if (mask_width, mask_height) != (width, height) {
+            return Err(anyhow::anyhow!(
+                "Mask size mismatch: expected {}x{}, got {}x{}",
+                width,
+                height,
+                mask_width,
+                mask_height
+            ));
+        }
+        for (dst, src) in data.chunks_exact_mut(4).zip(alpha.chunks_exact(4)) {
+            dst[3] = src[0];
+        }
+        self.import_rgba(filename, width, height, data, options)
+    }
+
+    /// Overlays an image onto the current pixels with its top left corner at `x`,`y`
+    /// and re-encodes the result.
+    ///
+    /// Parts outside of the CRX image are cut off. With `blend` the image is
+    /// composited over the current pixels using its alpha, otherwise it replaces them.
+    /// The size of the CRX image never changes, so `options.resize` and `options.trim`
+    /// are rejected.
+    pub fn import_region<F: AsRef<Path> + ?Sized>(
+        &mut self,
+        filename: &F,
+        x: i32,
+        y: i32,
+        blend: bool,
+        options: &ImportOptions,
+    ) -> Result<()> {
+        if options.resize || options.trim {
+            return Err(anyhow::anyhow!(
+                "--resize and --trim can not be used when importing a region"
+            ));
+        }
+        let (width, height, data) = Self::read_image(filename)?;
+        let left = x.max(0);
+        let top = y.max(0);
+        let right = (x + width as i32).min(self.width as i32);
+        let bottom = (y + height as i32).min(self.height as i32);
+        if left >= right || top >= bottom {
+            return Err(anyhow::anyhow!(
+                "Region {}x{} at ({}, {}) is outside of the {}x{} image",
+                width,
+                height,
+                x,
+                y,
+                self.width,
+                self.height
+            ));
+        }
+        if (right - left, bottom - top) != (width as i32, height as i32) {
+            eprintln!(
+                "Warning: region {}x{} at ({}, {}) is cut to {}x{} at ({}, {}).",
+                width,
+                height,
+                x,
+                y,
+                right - left,
+                bottom - top,
+                left,
+                top
+            );
+        }
+        let (region_width, region_height) = ((right - left) as usize, (bottom - top) as usize);
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        let stride = self.width as usize * pixel_size;
+        let row_range = |row: usize| {
+            let start = (top as usize + row) * stride + left as usize * pixel_size;
+            start..start + region_width * pixel_size
+        };
+        let mut src = Vec::with_capacity(region_width * region_height * 4);
+        for row in 0..region_height {
+            let start = ((top - y) as usize + row) * width as usize * 4 + (left - x) as usize * 4;
+            src.extend_from_slice(&data[start..start + region_width * 4]);
+        }
+        let mut original = Vec::with_capacity(region_width * region_height * pixel_size);
+        for row in 0..region_height {
+            original.extend_from_slice(&self.data[row_range(row)]);
+        }
+        let region = if blend && self.bpp == 0 {
+            Self::blend_rgba(&src, &original, pixel_size)
+        } else if blend {
+            // Only the color policy for transparent pixels applies to blended RGBA pixels.
+            let region = Self::blend_rgba(&src, &original, pixel_size);
+            Self::convert_rgba(region, region_width as u32, self.bpp, options, None)?
+        } else {
+            Self::convert_rgba(src, region_width as u32, self.bpp, options, Some(&original))?
+        };
+        if region == original && !options.changes_encoding(self.codec) {
+            eprintln!("Image data is unchanged, keeping the original CRX data.");
+            return Ok(());
+        }
+        for (row, pixels) in region.chunks_exact(region_width * pixel_size).enumerate() {
+            self.data[row_range(row)].copy_from_slice(pixels);
+        }
+        self.encode(options)
+    }
+
+    /// Composites RGBA pixels over RGB or RGBA pixels (`pixel_size` 3 or 4).
+    fn blend_rgba(src: &[u8], dst: &[u8], pixel_size: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(dst.len());
+        for (s, d) in src.chunks_exact(4).zip(dst.chunks_exact(pixel_size)) {
+            let sa = s[3] as u32;
+            let da = if pixel_size == 4 { d[3] as u32 } else { 255 };
+            // Alpha of the result scaled by 255.
+            let oa = sa * 255 + da * (255 - sa);
+            for c in 0..3 {
+                let v = (s[c] as u32 * sa * 255 + d[c] as u32 * da * (255 - sa) + oa / 2)
+                    .checked_div(oa)
+                    .unwrap_or(0);
+                out.push(v as u8);
+            }
+            if pixel_size == 4 {
+                out.push(((oa + 127) / 255) as u8);
+            }
+        }
+        out
+    }
+
+    /// Imports RGBA pixels read from `filename`.
+    fn import_rgba<F: AsRef<Path> + ?Sized>(
+        &mut self,
+        filename: &F,
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+        options: &ImportOptions,
+    ) -> Result<()> {
+        let original = if width == self.width as u32 && height == self.height as u32 {
+            Some(self.data.as_slice())
+        } else {
+            None
+        };
+        let data = Self::convert_rgba(data, width, self.bpp, options, original)?;
+        let metadata = CrxMetadata::read_for(filename)?;
+        if let Some(metadata) = &metadata {
+            self.apply_metadata(metadata)?;
+        }
+        if options.trim {
+            let (width, height) = Self::checked_size(width, height)?;
+            self.data = data;
+            if metadata.is_none() {
+                // Without metadata the image is a full canvas starting at the screen origin.
+                self.reframe(width, height, self.inner_x as i32, self.inner_y as i32)?;
+            } else {
+                self.reframe(width, height, 0, 0)?;
+            }
+            self.trim()?;
+            return self.encode(options);
+        }
+        if options.resize && (width != self.width as u32 || height != self.height as u32) {
+            let (width, height) = Self::checked_size(width, height)?;
+            self.data = data;
+            self.resize(width, height, options.anchor)?;
+            return self.encode(options);
+        }
+        if width != self.width as u32 {
             return Err(anyhow::anyhow!(
                 "Image width mismatch: expected {}, got {}",
                 self.width,
-                info.width
+                width
             ));
         }
-        if info.height != self.height as u32 {
+        if height != self.height as u32 {
             return Err(anyhow::anyhow!(
                 "Image height mismatch: expected {}, got {}",
                 self.height,
-                info.height
+                height
             ));
         }
-        if info.bit_depth != png::BitDepth::Eight {
-            return Err(anyhow::anyhow!(
-                "Image bit depth mismatch: expected 8, got {:?}",
-                info.bit_depth
-            ));
+        if data == self.data && !options.changes_encoding(self.codec) {
+            eprintln!("Image data is unchanged, keeping the original CRX data.");
+            return Ok(());
         }
-        if info.color_type != png::ColorType::Rgb && info.color_type != png::ColorType::Rgba {
+        self.data = data;
+        self.encode(options)
+    }
+
+    /// Changes the image size to match `data`, moving `inner_x`/`inner_y` so that
+    /// `anchor` stays in place, and regenerates the row types for the new size.
+    fn resize(&mut self, width: i16, height: i16, anchor: Anchor) -> Result<()> {
+        let (dx, dy) = anchor.offset(
+            width as i32 - self.width as i32,
+            height as i32 - self.height as i32,
+        );
+        self.reframe(width, height, dx, dy)
+    }
+
+    /// Crops fully transparent borders of a 32bpp image, moving `inner_x`/`inner_y`
+    /// so that the remaining pixels stay in place on screen.
+    fn trim(&mut self) -> Result<()> {
+        if self.bpp != 1 {
             return Err(anyhow::anyhow!(
-                "Image color type mismatch: expected RGB or RGBA, got {:?}",
-                info.color_type
+                "--trim needs a 32bpp CRX image, this image is 24bpp"
             ));
         }
-        let ct = info.color_type;
+        let width = self.width as usize;
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (i, _) in self
+            .data
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, p)| p[3] != 0)
+        {
+            let (x, y) = (i % width, i / width);
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((l, t, r, b)) => (l.min(x), t.min(y), r.max(x), b.max(y)),
+            });
+        }
+        let (left, top, right, bottom) = bounds.ok_or(anyhow::anyhow!(
+            "Image is fully transparent, nothing is left after trimming"
+        ))?;
+        let (w, h) = (right - left + 1, bottom - top + 1);
+        if (w, h) == (width, self.height as usize) {
+            return Ok(());
+        }
+        eprintln!(
+            "Trimmed {}x{} to {}x{} at ({}, {}).",
+            self.width, self.height, w, h, left, top
+        );
+        let mut data = Vec::with_capacity(w * h * 4);
+        for y in top..=bottom {
+            data.extend_from_slice(&self.data[(y * width + left) * 4..(y * width + right + 1) * 4]);
+        }
+        self.data = data;
+        self.reframe(w as i16, h as i16, -(left as i32), -(top as i32))
+    }
+
+    /// Changes the image size to match `data`, where the old top left corner is at
+    /// `dx`,`dy` in the new image. Moves `inner_x`/`inner_y` and the clips so that
+    /// they stay in place, and regenerates the row types for the new size. Clips
+    /// are clamped to the new bounds, and dropped if nothing of them is left.
+    fn reframe(&mut self, width: i16, height: i16, dx: i32, dy: i32) -> Result<()> {
+        self.inner_x = i16::try_from(self.inner_x as i32 - dx)
+            .map_err(|_| anyhow::anyhow!("inner_x out of range after resize"))?;
+        self.inner_y = i16::try_from(self.inner_y as i32 - dy)
+            .map_err(|_| anyhow::anyhow!("inner_y out of range after resize"))?;
+        self.clips.retain_mut(|clip| {
+            let (cx, cy) = (clip.field_4 as i32 + dx, clip.field_6 as i32 + dy);
+            let x = cx.clamp(0, width as i32);
+            let y = cy.clamp(0, height as i32);
+            let w = (clip.field_8 as i32 - (x - cx)).clamp(0, width as i32 - x);
+            let h = (clip.field_a as i32 - (y - cy)).clamp(0, height as i32 - y);
+            if w == 0 || h == 0 {
+                eprintln!(
+                    "Clip {:?} is outside of the new image bounds, removing it.",
+                    clip
+                );
+                return false;
+            }
+            if (x, y, w, h) != (cx, cy, clip.field_8 as i32, clip.field_a as i32) {
+                eprintln!("Clip {:?} was clamped to the new image bounds.", clip);
+            }
+            clip.field_4 = x as i16;
+            clip.field_6 = y as i16;
+            clip.field_8 = w as i16;
+            clip.field_a = h as i16;
+            true
+        });
+        self.width = width;
+        self.height = height;
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        self.encode_type = Self::generate_encode_type(&self.data, width, height, pixel_size)?;
+        Ok(())
+    }
+
+    /// Checks that an image size can be stored in a CRX header.
+    fn checked_size(width: u32, height: u32) -> Result<(i16, i16)> {
+        let width = i16::try_from(width)
+            .ok()
+            .filter(|&w| w > 0)
+            .ok_or(anyhow::anyhow!("Unsupported image width: {}", width))?;
+        let height = i16::try_from(height)
+            .ok()
+            .filter(|&h| h > 0)
+            .ok_or(anyhow::anyhow!("Unsupported image height: {}", height))?;
+        Ok((width, height))
+    }
+
+    /// Reads an image file as 8-bit RGBA pixels, in the format given by its extension.
+    fn read_image<F: AsRef<Path> + ?Sized>(filename: &F) -> Result<(u32, u32, Vec<u8>)> {
+        match ImageFormat::from_path(filename) {
+            Some(ImageFormat::Png) => Self::read_png(filename),
+            Some(_) => {
+                let image = image::ImageReader::open(filename)?.decode()?;
+                if !matches!(
+                    image.color(),
+                    image::ColorType::Rgb8
+                        | image::ColorType::Rgba8
+                        | image::ColorType::L8
+                        | image::ColorType::La8
+                ) {
+                    eprintln!(
+                        "Warning: {} is converted from {:?} to 8-bit RGBA.",
+                        filename.as_ref().display(),
+                        image.color()
+                    );
+                }
+                let image = image.into_rgba8();
+                Ok((image.width(), image.height(), image.into_raw()))
+            }
+            None => Err(anyhow::anyhow!(
+                "Unsupported image format: {}",
+                filename.as_ref().display()
+            )),
+        }
+    }
+
+    /// Reads a PNG file as 8-bit RGBA pixels.
+    ///
+    /// Any PNG color type and bit depth is accepted. Palettes and grayscale are
+    /// expanded, tRNS transparency becomes an alpha channel and 16-bit samples
+    /// are reduced to 8 bits.
+    fn read_png<F: AsRef<Path> + ?Sized>(filename: &F) -> Result<(u32, u32, Vec<u8>)> {
+        let f = std::fs::File::open(filename)?;
+        let mut decoder = png::Decoder::new(std::io::BufReader::new(f));
+        decoder.set_transformations(png::Transformations::EXPAND);
         let mut reader = decoder.read_info()?;
-        let size = self.width as usize
-            * self.height as usize
-            * if ct == png::ColorType::Rgb { 3 } else { 4 };
-        let mut data = Vec::with_capacity(size);
-        data.resize(size, 0);
-        reader.next_frame(&mut data)?;
-        let data = if self.bpp == 0 && ct == png::ColorType::Rgba {
-            Self::rgba_to_rgb(&data)
-        } else if self.bpp == 1 && ct == png::ColorType::Rgb {
-            Self::rgb_to_rgba(&data)
+        let (src_ct, src_depth) = {
+            let info = reader.info();
+            (info.color_type, info.bit_depth)
+        };
+        let (ct, depth) = reader.output_color_type();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+        buf.truncate(frame.buffer_size());
+        let (width, height) = (frame.width, frame.height);
+        let buf = if depth == png::BitDepth::Sixteen {
+            let lossy = buf.chunks_exact(2).any(|c| c[0] != c[1]);
+            if lossy {
+                eprintln!(
+                    "Warning: {} is a 16-bit PNG, reducing it to 8 bits loses precision.",
+                    filename.as_ref().display()
+                );
+            }
+            buf.chunks_exact(2).map(|c| c[0]).collect()
         } else {
-            data
+            buf
+        };
+        let data = match ct {
+            png::ColorType::Grayscale => buf.iter().flat_map(|&v| [v, v, v]).collect(),
+            png::ColorType::GrayscaleAlpha => buf
+                .chunks_exact(2)
+                .flat_map(|c| [c[0], c[0], c[0], c[1]])
+                .collect(),
+            png::ColorType::Rgb | png::ColorType::Rgba => buf,
+            png::ColorType::Indexed => {
+                return Err(anyhow::anyhow!(
+                    "Failed to expand palette of {:?} {:?}-bit PNG",
+                    src_ct,
+                    src_depth
+                ));
+            }
         };
-        let edata = if self.bpp == 0 {
-            Self::encode_image_bbp24(&data, self.width, self.height, &self.encode_type)?
+        let has_alpha = matches!(ct, png::ColorType::Rgba | png::ColorType::GrayscaleAlpha);
+        let data = if has_alpha {
+            data
         } else {
-            Self::encode_image_bbp32(&data, self.width, self.height, &self.encode_type)?
+            Self::rgb_to_rgba(&data)
         };
-        let compressed_data = utils::compress_data(&edata)?;
-        self.data = data;
-        self.compressed_data = compressed_data;
+        Ok((width, height, data))
+    }
+
+    /// Converts RGBA pixels to the layout used by `bpp`.
+    ///
+    /// For 24bpp images, pixels that are not fully opaque are composited over
+    /// `options.matte`. `original` holds the pixels of the original image and is
+    /// needed for [Matte::Original]. For 32bpp images, the color of fully
+    /// transparent pixels is changed according to `options.transparent`.
+    fn convert_rgba(
+        mut data: Vec<u8>,
+        width: u32,
+        bpp: i16,
+        options: &ImportOptions,
+        original: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        if bpp != 0 {
+            match options.transparent {
+                Transparent::Preserve => {}
+                Transparent::Zero => {
+                    for p in data.chunks_exact_mut(4).filter(|p| p[3] == 0) {
+                        p[..3].fill(0);
+                    }
+                }
+                Transparent::Bleed => Self::bleed_transparent(&mut data, width as usize),
+            }
+            return Ok(data);
+        }
+        let translucent = data.chunks_exact(4).filter(|p| p[3] != 0xff).count();
+        let matte = match options.matte {
+            _ if translucent == 0 => return Ok(Self::rgba_to_rgb(&data)),
+            None => {
+                eprintln!(
+                    "Warning: {} pixels are not fully opaque, their alpha is dropped. Use --matte to composite them instead.",
+                    translucent
+                );
+                return Ok(Self::rgba_to_rgb(&data));
+            }
+            Some(Matte::Color(color)) => color.repeat(data.len() / 4),
+            Some(Matte::Original) => original
+                .ok_or(anyhow::anyhow!(
+                    "--matte original requires an original CRX image of the same size"
+                ))?
+                .to_vec(),
+        };
+        let mut dst = Vec::with_capacity(matte.len());
+        for (src, bg) in data.chunks_exact(4).zip(matte.chunks_exact(3)) {
+            let a = src[3] as u32;
+            for c in 0..3 {
+                let v = (src[c] as u32 * a + bg[c] as u32 * (255 - a) + 127) / 255;
+                dst.push(v as u8);
+            }
+        }
+        Ok(dst)
+    }
+
+    /// Fills the color of fully transparent RGBA pixels with the average of their
+    /// already filled neighbours, growing outwards from the visible pixels one
+    /// ring at a time. Pixels that cannot be reached are set to black.
+    fn bleed_transparent(data: &mut [u8], width: usize) {
+        let count = data.len() / 4;
+        if width == 0 || count == 0 {
+            return;
+        }
+        let height = count / width;
+        let neighbours = |i: usize| {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            (-1..=1)
+                .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+                .filter(move |&(nx, ny)| {
+                    (nx, ny) != (x, y)
+                        && nx >= 0
+                        && ny >= 0
+                        && (nx as usize) < width
+                        && (ny as usize) < height
+                })
+                .map(move |(nx, ny)| ny as usize * width + nx as usize)
+        };
+        let mut filled: Vec<bool> = data.chunks_exact(4).map(|p| p[3] != 0).collect();
+        let mut queued = filled.clone();
+        let mut ring: Vec<usize> = (0..count)
+            .filter(|&i| !filled[i] && neighbours(i).any(|j| filled[j]))
+            .collect();
+        for &i in &ring {
+            queued[i] = true;
+        }
+        while !ring.is_empty() {
+            let colors: Vec<[u8; 3]> = ring
+                .iter()
+                .map(|&i| {
+                    let mut sum = [0u32; 3];
+                    let mut n = 0;
+                    for j in neighbours(i).filter(|&j| filled[j]) {
+                        for c in 0..3 {
+                            sum[c] += data[j * 4 + c] as u32;
+                        }
+                        n += 1;
+                    }
+                    sum.map(|v| ((v + n / 2) / n) as u8)
+                })
+                .collect();
+            for (&i, color) in ring.iter().zip(colors) {
+                data[i * 4..i * 4 + 3].copy_from_slice(&color);
+                filled[i] = true;
+            }
+            let mut next = Vec::new();
+            for &i in &ring {
+                for j in neighbours(i) {
+                    if !queued[j] {
+                        queued[j] = true;
+                        next.push(j);
+                    }
+                }
+            }
+            ring = next;
+        }
+        for (p, _) in data
+            .chunks_exact_mut(4)
+            .zip(&filled)
+            .filter(|(_, filled)| !**filled)
+        {
+            p[..3].fill(0);
+        }
+    }
+
+    /// Chooses the row types according to `options`, then encodes and compresses `data`.
+    fn encode(&mut self, options: &ImportOptions) -> Result<()> {
+        let pixel_size = if self.bpp == 0 { 3 } else { 4 };
+        self.codec = options.codec.unwrap_or(self.codec);
+        let compress = |encode_type: &[u8]| -> Result<Vec<u8>> {
+            let edata =
+                Self::encode_image(&self.data, self.width, self.height, encode_type, pixel_size)?;
+            Ok(utils::compress_data(&edata, self.codec, options.level)?)
+        };
+        let heuristic =
+            || Self::generate_encode_type(&self.data, self.width, self.height, pixel_size);
+        let (encode_type, compressed_data) = match options.row_filter {
+            RowFilter::Original => (None, compress(&self.encode_type)?),
+            RowFilter::Heuristic => {
+                let encode_type = heuristic()?;
+                let compressed_data = compress(&encode_type)?;
+                (Some(encode_type), compressed_data)
+            }
+            RowFilter::Best => {
+                // Rows are measured on their own, which can lose to the heuristic once
+                // the whole stream is compressed, so keep whichever is smaller.
+                let best = Self::best_encode_type(
+                    &self.data,
+                    self.width,
+                    self.height,
+                    pixel_size,
+                    self.codec,
+                )?;
+                let best_data = compress(&best)?;
+                let heuristic = heuristic()?;
+                let heuristic_data = compress(&heuristic)?;
+                if best_data.len() <= heuristic_data.len() {
+                    (Some(best), best_data)
+                } else {
+                    (Some(heuristic), heuristic_data)
+                }
+            }
+        };
+        if let Some(encode_type) = encode_type {
+            self.encode_type = encode_type;
+        }
+        self.compressed_data = Cow::Owned(compressed_data);
+        self.reencoded = true;
         Ok(())
     }
 
+    /// Picks a row type for every row by the smallest sum of absolute residuals.
+    ///
+    /// In the first row, row types 1 and 3 store the raw pixels and row type 2 is the same
+    /// as row type 0, so it always uses row type 0.
+    fn generate_encode_type(
+        data: &[u8],
+        width: i16,
+        height: i16,
+        pixel_size: i8,
+    ) -> Result<Vec<u8>> {
+        let mut encode_type = Vec::with_capacity(height as usize);
+        let mut row = Vec::with_capacity(width as usize * pixel_size as usize);
+        for y in 0..height {
+            if y == 0 {
+                encode_type.push(0);
+                continue;
+            }
+            let mut best = (0, u64::MAX);
+            for row_type in 0..4 {
+                row.clear();
+                Self::encode_row(&mut row, data, width, y, row_type, pixel_size)?;
+                let cost = row
+                    .iter()
+                    .map(|&b| (b as i8).unsigned_abs() as u64)
+                    .sum::<u64>();
+                if cost < best.1 {
+                    best = (row_type, cost);
+                }
+            }
+            encode_type.push(best.0);
+        }
+        Ok(encode_type)
+    }
+
+    /// Encodes every row with all row types and keeps the one with the smallest
+    /// size after compressing it with `codec` at a fast level.
+    ///
+    /// In the first row, row type 2 predicts from the left pixel and would duplicate row
+    /// type 0, while row types 1 and 3 store the raw pixels as deltas against zeros, so
+    /// only row types 0 and 4 are tried there.
+    fn best_encode_type(
+        data: &[u8],
+        width: i16,
+        height: i16,
+        pixel_size: i8,
+        codec: Codec,
+    ) -> Result<Vec<u8>> {
+        // Every row is compressed five times, the output level would be far too slow.
+        let mut zstd = match codec {
+            Codec::Zstd => Some(zstd::bulk::Compressor::new(3)?),
+            Codec::Zlib => None,
+        };
+        let mut compressed_size = |row: &[u8]| match zstd.as_mut() {
+            Some(zstd) => zstd.compress(row).map(|c| c.len()),
+            None => utils::compress_zlib(row, 1).map(|c| c.len()),
+        };
+        let mut encode_type = Vec::with_capacity(height as usize);
+        let mut row = Vec::with_capacity(width as usize * pixel_size as usize);
+        for y in 0..height {
+            let candidates: &[u8] = if y == 0 { &[0, 4] } else { &[0, 1, 2, 3, 4] };
+            let mut best = (0, usize::MAX);
+            for &row_type in candidates {
+                row.clear();
+                Self::encode_row(&mut row, data, width, y, row_type, pixel_size)?;
+                let size = compressed_size(&row)?;
+                if size < best.1 {
+                    best = (row_type, size);
+                }
+            }
+            encode_type.push(best.0);
+        }
+        Ok(encode_type)
+    }
+
     pub fn write_to_file<F: AsRef<Path> + ?Sized>(&self, filename: &F) -> Result<()> {
         let f = std::fs::File::create(filename)?;
         let mut f = std::io::BufWriter::new(f);
@@ -230,13 +1696,19 @@ impl Crx {
     }
 
     pub fn write_to<W: Write>(&self, f: &mut W) -> Result<()> {
+        // Re-encoded data always stores its size, untouched data keeps the original flags.
+        let flags = if self.reencoded {
+            self.flags | 0x10
+        } else {
+            self.flags
+        };
         f.write_i32(MAGIC)?;
         f.write_i16(self.inner_x)?;
         f.write_i16(self.inner_y)?;
         f.write_i16(self.width)?;
         f.write_i16(self.height)?;
         f.write_i16(self.version)?;
-        f.write_i16(self.flags | 0x10)?;
+        f.write_i16(flags)?;
         f.write_i16(self.bpp)?;
         f.write_i16(self.unknown)?;
         if self.version >= 3 {
@@ -251,618 +1723,419 @@ impl Crx {
                 f.write_i16(clip.field_e)?;
             }
         }
-        f.write_i32(self.compressed_data.len() as i32)?;
+        if (flags & 0x10) != 0 {
+            f.write_i32(self.compressed_data.len() as i32)?;
+        }
         f.write_all(&self.compressed_data)?;
         Ok(())
     }
 
-    fn decode_row0(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        mut src_p: usize,
-        width: i16,
-        pixel_size: i8,
-    ) -> Result<usize> {
-        let mut prev_p = dst_p;
-        for _ in 0..pixel_size {
-            dst[dst_p] = src[src_p];
-            dst_p += 1;
-            src_p += 1;
-        }
-        let remaining = width - 1;
-        for _ in 0..remaining {
-            for _ in 0..pixel_size {
-                dst[dst_p] = src[src_p].overflowing_add(dst[prev_p]).0;
-                dst_p += 1;
-                src_p += 1;
-                prev_p += 1;
-            }
+    /// Decodes a row stored without a filter, predicting each pixel from its left neighbour.
+    fn decode_row0(dst: &mut [u8], src: &[u8], pixel_size: usize) {
+        dst[..pixel_size].copy_from_slice(&src[..pixel_size]);
+        for i in pixel_size..dst.len() {
+            dst[i] = src[i].wrapping_add(dst[i - pixel_size]);
         }
-        Ok(src_p)
     }
 
-    fn decode_row1(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        mut src_p: usize,
-        width: i16,
-        pixel_size: i8,
-        mut prev_row_p: usize,
-    ) -> Result<usize> {
-        for _ in 0..width {
-            for _ in 0..pixel_size {
-                dst[dst_p] = src[src_p].overflowing_add(dst[prev_row_p]).0;
-                dst_p += 1;
-                src_p += 1;
-                prev_row_p += 1;
-            }
+    /// Decodes a row predicted from the pixel above.
+    fn decode_row1(dst: &mut [u8], src: &[u8], prev: &[u8]) {
+        for i in 0..dst.len() {
+            dst[i] = src[i].wrapping_add(prev[i]);
         }
-        Ok(src_p)
     }
 
-    fn decode_row2(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        mut src_p: usize,
-        width: i16,
-        pixel_size: i8,
-        mut prev_row_p: usize,
-    ) -> Result<usize> {
-        for _ in 0..pixel_size {
-            dst[dst_p] = src[src_p];
-            dst_p += 1;
-            src_p += 1;
+    /// Decodes a row predicted from the pixel above on the left.
+    ///
+    /// Row 0 has no row above, so row type 2 is decoded with [Crx::decode_row0] there.
+    fn decode_row2(dst: &mut [u8], src: &[u8], prev: &[u8], pixel_size: usize) {
+        dst[..pixel_size].copy_from_slice(&src[..pixel_size]);
+        for i in pixel_size..dst.len() {
+            dst[i] = src[i].wrapping_add(prev[i - pixel_size]);
         }
-        let remaining = width - 1;
-        for _ in 0..remaining {
-            for _ in 0..pixel_size {
-                dst[dst_p] = src[src_p].overflowing_add(dst[prev_row_p]).0;
-                dst_p += 1;
-                src_p += 1;
-                prev_row_p += 1;
-            }
-        }
-        Ok(src_p)
     }
 
-    fn decode_row3(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        mut src_p: usize,
-        width: i16,
-        pixel_size: i8,
-        mut prev_row_p: usize,
-    ) -> Result<usize> {
-        let count = width - 1;
-        prev_row_p += pixel_size as usize;
-        for _ in 0..count {
-            for _ in 0..pixel_size {
-                dst[dst_p] = src[src_p].overflowing_add(dst[prev_row_p]).0;
-                dst_p += 1;
-                src_p += 1;
-                prev_row_p += 1;
-            }
+    /// Decodes a row predicted from the pixel above on the right.
+    fn decode_row3(dst: &mut [u8], src: &[u8], prev: &[u8], pixel_size: usize) {
+        let last = dst.len() - pixel_size;
+        for i in 0..last {
+            dst[i] = src[i].wrapping_add(prev[i + pixel_size]);
         }
-        for _ in 0..pixel_size {
-            dst[dst_p] = src[src_p];
-            dst_p += 1;
-            src_p += 1;
-        }
-        Ok(src_p)
+        dst[last..].copy_from_slice(&src[last..]);
     }
 
-    fn decode_row4(
-        dst: &mut Vec<u8>,
-        dst_p: usize,
-        src: &[u8],
-        mut src_p: usize,
-        width: i16,
-        pixel_size: i8,
-    ) -> Result<usize> {
-        for offset in 0..pixel_size {
-            let mut dst_c = dst_p + offset as usize;
-            let mut remaining = width;
-            let value = src[src_p];
-            src_p += 1;
-            dst[dst_c] = value;
-            dst_c += pixel_size as usize;
-            remaining -= 1;
-            if remaining == 0 {
-                continue;
-            }
-            if value == src[src_p] {
-                src_p += 1;
-                let count = src[src_p] as i16;
-                src_p += 1;
-                remaining -= count;
-                for _ in 0..count {
-                    dst[dst_c] = value;
-                    dst_c += pixel_size as usize;
-                }
-            }
+    /// Decodes a row stored as run-length encoded channels.
+    fn decode_row4<R: BufRead>(
+        dst: &mut [u8],
+        src: &mut RowReader<R>,
+        pixel_size: usize,
+        y: i16,
+    ) -> Result<()> {
+        let width = dst.len() / pixel_size;
+        for offset in 0..pixel_size {
+            let mut dst_c = offset;
+            let mut remaining = width;
             while remaining > 0 {
-                let value = src[src_p];
-                src_p += 1;
+                let value = src.read_byte(y)?;
                 dst[dst_c] = value;
-                dst_c += pixel_size as usize;
+                dst_c += pixel_size;
                 remaining -= 1;
                 if remaining == 0 {
                     break;
                 }
-                if value == src[src_p] {
-                    src_p += 1;
-                    let count = src[src_p] as i16;
-                    src_p += 1;
+                if value == src.peek_byte(y)? {
+                    src.read_byte(y)?;
+                    let count = src.read_byte(y)? as usize;
+                    if count > remaining {
+                        return Err(anyhow::anyhow!(
+                            "Run of {} pixels exceeds the {} remaining pixels in row {} at byte offset {}",
+                            count,
+                            remaining,
+                            y,
+                            src.offset - 1
+                        ));
+                    }
                     remaining -= count;
                     for _ in 0..count {
                         dst[dst_c] = value;
-                        dst_c += pixel_size as usize;
+                        dst_c += pixel_size;
                     }
                 }
             }
         }
-        Ok(src_p)
+        Ok(())
     }
 
-    fn decode_image(
-        dst: &mut Vec<u8>,
-        src: &[u8],
-        width: i16,
-        height: i16,
-        pixel_size: i8,
-        encode_type: &mut Vec<u8>,
-    ) -> Result<()> {
-        let mut src_p = 0;
-        let mut dst_p = 0;
-        let mut prev_row_p = 0;
-        for _ in 0..height {
-            let data = src[src_p];
-            encode_type.push(data);
-            src_p += 1;
-            match data {
-                0 => {
-                    src_p = Self::decode_row0(dst, dst_p, src, src_p, width, pixel_size)?;
-                }
-                1 => {
-                    src_p =
-                        Self::decode_row1(dst, dst_p, src, src_p, width, pixel_size, prev_row_p)?;
-                }
-                2 => {
-                    src_p =
-                        Self::decode_row2(dst, dst_p, src, src_p, width, pixel_size, prev_row_p)?;
-                }
-                3 => {
-                    src_p =
-                        Self::decode_row3(dst, dst_p, src, src_p, width, pixel_size, prev_row_p)?;
-                }
-                4 => {
-                    src_p = Self::decode_row4(dst, dst_p, src, src_p, width, pixel_size)?;
-                }
-                _ => {
-                    return Err(anyhow::anyhow!("Invalid row type: {}", data));
-                }
-            }
-            prev_row_p = dst_p;
-            dst_p += pixel_size as usize * width as usize;
-        }
-        if pixel_size == 4 {
-            for p in (0..dst.len()).step_by(4) {
-                let a = dst[p + 0];
-                let b = dst[p + 1];
-                let g = dst[p + 2];
-                let r = dst[p + 3];
-                dst[p + 0] = r;
-                dst[p + 1] = g;
-                dst[p + 2] = b;
-                dst[p + 3] = 0xff - a;
-            }
-        } else if pixel_size == 3 {
-            for p in (0..dst.len()).step_by(3) {
-                let b = dst[p + 0];
-                let g = dst[p + 1];
-                let r = dst[p + 2];
-                dst[p + 0] = r;
-                dst[p + 1] = g;
-                dst[p + 2] = b;
+    /// Converts a decoded row from BGR, or ABGR with inverted alpha, to RGB or RGBA.
+    fn convert_row(dst: &mut [u8], src: &[u8], pixel_size: usize) {
+        for (d, s) in dst
+            .chunks_exact_mut(pixel_size)
+            .zip(src.chunks_exact(pixel_size))
+        {
+            if pixel_size == 4 {
+                d.copy_from_slice(&[s[3], s[2], s[1], 0xff - s[0]]);
+            } else {
+                d.copy_from_slice(&[s[2], s[1], s[0]]);
             }
         }
-        Ok(())
     }
 
-    fn encode_bbp24_row0(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp24_row0(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 3;
-        dst[dst_p] = src[src_p + 2];
-        dst[dst_p + 1] = src[src_p + 1];
-        dst[dst_p + 2] = src[src_p];
-        dst_p += 3;
+        dst.push(src[src_p + 2]);
+        dst.push(src[src_p + 1]);
+        dst.push(src[src_p]);
         src_p += 3;
         for _ in 1..width {
-            dst[dst_p] = src[src_p + 2].overflowing_sub(src[src_p - 1]).0;
-            dst[dst_p + 1] = src[src_p + 1].overflowing_sub(src[src_p - 2]).0;
-            dst[dst_p + 2] = src[src_p].overflowing_sub(src[src_p - 3]).0;
-            dst_p += 3;
+            dst.push(src[src_p + 2].overflowing_sub(src[src_p - 1]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[src_p - 2]).0);
+            dst.push(src[src_p].overflowing_sub(src[src_p - 3]).0);
             src_p += 3;
         }
-        return Ok(dst_p);
+        Ok(())
     }
 
-    fn encode_bbp24_row1(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp24_row1(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 3;
         let mut prev_row_p = (y - 1) as usize * width as usize * 3;
         for _ in 0..width {
-            dst[dst_p] = src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0;
-            dst[dst_p + 1] = src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0;
-            dst[dst_p + 2] = src[src_p].overflowing_sub(src[prev_row_p]).0;
-            dst_p += 3;
+            dst.push(src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0);
+            dst.push(src[src_p].overflowing_sub(src[prev_row_p]).0);
             src_p += 3;
             prev_row_p += 3;
         }
-        Ok(dst_p)
+        Ok(())
     }
 
-    fn encode_bbp24_row2(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp24_row2(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 3;
         let mut prev_row_p = (y - 1) as usize * width as usize * 3;
-        dst[dst_p] = src[src_p + 2];
-        dst[dst_p + 1] = src[src_p + 1];
-        dst[dst_p + 2] = src[src_p];
-        dst_p += 3;
+        dst.push(src[src_p + 2]);
+        dst.push(src[src_p + 1]);
+        dst.push(src[src_p]);
         src_p += 3;
         for _ in 1..width {
-            dst[dst_p] = src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0;
-            dst[dst_p + 1] = src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0;
-            dst[dst_p + 2] = src[src_p].overflowing_sub(src[prev_row_p]).0;
-            dst_p += 3;
+            dst.push(src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0);
+            dst.push(src[src_p].overflowing_sub(src[prev_row_p]).0);
             src_p += 3;
             prev_row_p += 3;
         }
-        Ok(dst_p)
+        Ok(())
     }
 
-    fn encode_bbp24_row3(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp24_row3(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 3;
         let mut prev_row_p = (y - 1) as usize * width as usize * 3 + 3;
         for _ in 0..width - 1 {
-            dst[dst_p] = src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0;
-            dst[dst_p + 1] = src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0;
-            dst[dst_p + 2] = src[src_p].overflowing_sub(src[prev_row_p]).0;
-            dst_p += 3;
+            dst.push(src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0);
+            dst.push(src[src_p].overflowing_sub(src[prev_row_p]).0);
             src_p += 3;
             prev_row_p += 3;
         }
-        dst[dst_p] = src[src_p + 2];
-        dst[dst_p + 1] = src[src_p + 1];
-        dst[dst_p + 2] = src[src_p];
-        dst_p += 3;
-        Ok(dst_p)
+        dst.push(src[src_p + 2]);
+        dst.push(src[src_p + 1]);
+        dst.push(src[src_p]);
+        Ok(())
     }
 
-    fn encode_bbp24_row4(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp24_row4(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let src_p = y as usize * width as usize * 3;
         for offset in 0..3 {
             let mut src_c = src_p + 2 - offset as usize;
             let mut remaining = width;
             let value = src[src_c];
             src_c += 3;
-            dst[dst_p] = value;
-            dst_p += 1;
+            dst.push(value);
             remaining -= 1;
             if remaining == 0 {
                 continue;
             }
             let mut count = 0;
             loop {
-                if count as i16 >= remaining || count >= 255 || src[src_c] != value {
+                if count as i16 >= remaining || count == 255 || src[src_c] != value {
                     break;
                 }
                 src_c += 3;
                 count += 1;
             }
             if count > 0 {
-                dst[dst_p] = value;
-                dst_p += 1;
-                dst[dst_p] = count;
-                dst_p += 1;
+                dst.push(value);
+                dst.push(count);
                 remaining -= count as i16;
             }
             while remaining > 0 {
                 let value = src[src_c];
                 src_c += 3;
-                dst[dst_p] = value;
-                dst_p += 1;
+                dst.push(value);
                 remaining -= 1;
                 if remaining == 0 {
                     break;
                 }
                 let mut count = 0;
                 loop {
-                    if count as i16 >= remaining || count >= 255 || src[src_c] != value {
+                    if count as i16 >= remaining || count == 255 || src[src_c] != value {
                         break;
                     }
                     src_c += 3;
                     count += 1;
                 }
                 if count > 0 {
-                    dst[dst_p] = value;
-                    dst_p += 1;
-                    dst[dst_p] = count;
-                    dst_p += 1;
+                    dst.push(value);
+                    dst.push(count);
                     remaining -= count as i16;
                 }
             }
         }
-        Ok(dst_p)
+        Ok(())
+    }
+
+    fn encode_row(
+        dst: &mut Vec<u8>,
+        src: &[u8],
+        width: i16,
+        y: i16,
+        row_type: u8,
+        pixel_size: i8,
+    ) -> Result<()> {
+        if y == 0 && row_type == 2 {
+            // Row 0 has no row above, so the decoder predicts from the left pixel.
+            return Self::encode_row(dst, src, width, y, 0, pixel_size);
+        }
+        if y == 0 && (row_type == 1 || row_type == 3) {
+            // The decoder reads zeros in place of the row above, which is black and, as
+            // alpha is stored inverted, opaque.
+            let row_size = width as usize * pixel_size as usize;
+            let mut rows = vec![0; row_size * 2];
+            if pixel_size == 4 {
+                rows[..row_size]
+                    .chunks_exact_mut(4)
+                    .for_each(|p| p[3] = 0xff);
+            }
+            rows[row_size..].copy_from_slice(&src[..row_size]);
+            return Self::encode_row(dst, &rows, width, 1, row_type, pixel_size);
+        }
+        match (pixel_size, row_type) {
+            (3, 0) => Self::encode_bbp24_row0(dst, src, width, y),
+            (3, 1) => Self::encode_bbp24_row1(dst, src, width, y),
+            (3, 2) => Self::encode_bbp24_row2(dst, src, width, y),
+            (3, 3) => Self::encode_bbp24_row3(dst, src, width, y),
+            (3, 4) => Self::encode_bbp24_row4(dst, src, width, y),
+            (4, 0) => Self::encode_bbp32_row0(dst, src, width, y),
+            (4, 1) => Self::encode_bbp32_row1(dst, src, width, y),
+            (4, 2) => Self::encode_bbp32_row2(dst, src, width, y),
+            (4, 3) => Self::encode_bbp32_row3(dst, src, width, y),
+            (4, 4) => Self::encode_bbp32_row4(dst, src, width, y),
+            _ => Err(anyhow::anyhow!(
+                "Invalid row type: {} on line {}",
+                row_type,
+                y
+            )),
+        }
     }
 
-    fn encode_image_bbp24(src: &[u8], width: i16, height: i16, row_type: &[u8]) -> Result<Vec<u8>> {
-        let size = width as usize * height as usize * 3 + height as usize;
+    /// Encodes an image with the given row types.
+    ///
+    /// Row type 4 can produce more bytes than the raw pixels, so the output grows as needed.
+    fn encode_image(
+        src: &[u8],
+        width: i16,
+        height: i16,
+        row_type: &[u8],
+        pixel_size: i8,
+    ) -> Result<Vec<u8>> {
+        if row_type.len() < height as usize {
+            return Err(anyhow::anyhow!(
+                "Missing row types: expected {}, got {}",
+                height,
+                row_type.len()
+            ));
+        }
+        let size = width as usize * height as usize * pixel_size as usize + height as usize;
         let mut dst = Vec::with_capacity(size);
-        dst.resize(size, 0);
-        let mut dst_p = 0;
         for y in 0..height {
             let data = row_type[y as usize];
-            dst[dst_p] = data;
-            dst_p += 1;
-            match data {
-                0 => {
-                    dst_p = Self::encode_bbp24_row0(&mut dst, dst_p, src, width, y)?;
-                }
-                1 => {
-                    dst_p = Self::encode_bbp24_row1(&mut dst, dst_p, src, width, y)?;
-                }
-                2 => {
-                    dst_p = Self::encode_bbp24_row2(&mut dst, dst_p, src, width, y)?;
-                }
-                3 => {
-                    dst_p = Self::encode_bbp24_row3(&mut dst, dst_p, src, width, y)?;
-                }
-                4 => {
-                    eprintln!("Encoding row type 4 on y={}", y);
-                    dst_p = Self::encode_bbp24_row4(&mut dst, dst_p, src, width, y)?;
-                }
-                _ => {
-                    return Err(anyhow::anyhow!("Invalid row type: {}", data));
-                }
-            }
+            dst.push(data);
+            Self::encode_row(&mut dst, src, width, y, data, pixel_size)?;
         }
         Ok(dst)
     }
 
-    fn encode_bbp32_row0(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp32_row0(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 4;
-        dst[dst_p] = 0xff - src[src_p + 3];
-        dst[dst_p + 1] = src[src_p + 2];
-        dst[dst_p + 2] = src[src_p + 1];
-        dst[dst_p + 3] = src[src_p];
-        dst_p += 4;
+        dst.push(0xff - src[src_p + 3]);
+        dst.push(src[src_p + 2]);
+        dst.push(src[src_p + 1]);
+        dst.push(src[src_p]);
         src_p += 4;
         for _ in 1..width {
-            dst[dst_p] = (0xff - src[src_p + 3])
-                .overflowing_sub(0xff - src[src_p - 1])
-                .0;
-            dst[dst_p + 1] = src[src_p + 2].overflowing_sub(src[src_p - 2]).0;
-            dst[dst_p + 2] = src[src_p + 1].overflowing_sub(src[src_p - 3]).0;
-            dst[dst_p + 3] = src[src_p].overflowing_sub(src[src_p - 4]).0;
-            dst_p += 4;
+            dst.push(
+                (0xff - src[src_p + 3])
+                    .overflowing_sub(0xff - src[src_p - 1])
+                    .0,
+            );
+            dst.push(src[src_p + 2].overflowing_sub(src[src_p - 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[src_p - 3]).0);
+            dst.push(src[src_p].overflowing_sub(src[src_p - 4]).0);
             src_p += 4;
         }
-        return Ok(dst_p);
+        Ok(())
     }
 
-    fn encode_bbp32_row1(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp32_row1(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 4;
         let mut prev_row_p = (y - 1) as usize * width as usize * 4;
         for _ in 0..width {
-            dst[dst_p] = (0xff - src[src_p + 3])
-                .overflowing_sub(0xff - src[prev_row_p + 3])
-                .0;
-            dst[dst_p + 1] = src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0;
-            dst[dst_p + 2] = src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0;
-            dst[dst_p + 3] = src[src_p].overflowing_sub(src[prev_row_p]).0;
-            dst_p += 4;
+            dst.push(
+                (0xff - src[src_p + 3])
+                    .overflowing_sub(0xff - src[prev_row_p + 3])
+                    .0,
+            );
+            dst.push(src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0);
+            dst.push(src[src_p].overflowing_sub(src[prev_row_p]).0);
             src_p += 4;
             prev_row_p += 4;
         }
-        Ok(dst_p)
+        Ok(())
     }
 
-    fn encode_bbp32_row2(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp32_row2(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 4;
         let mut prev_row_p = (y - 1) as usize * width as usize * 4;
-        dst[dst_p] = 0xff - src[src_p + 3];
-        dst[dst_p + 1] = src[src_p + 2];
-        dst[dst_p + 2] = src[src_p + 1];
-        dst[dst_p + 3] = src[src_p];
-        dst_p += 4;
+        dst.push(0xff - src[src_p + 3]);
+        dst.push(src[src_p + 2]);
+        dst.push(src[src_p + 1]);
+        dst.push(src[src_p]);
         src_p += 4;
         for _ in 1..width {
-            dst[dst_p] = (0xff - src[src_p + 3])
-                .overflowing_sub(0xff - src[prev_row_p + 3])
-                .0;
-            dst[dst_p + 1] = src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0;
-            dst[dst_p + 2] = src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0;
-            dst[dst_p + 3] = src[src_p].overflowing_sub(src[prev_row_p]).0;
-            dst_p += 4;
+            dst.push(
+                (0xff - src[src_p + 3])
+                    .overflowing_sub(0xff - src[prev_row_p + 3])
+                    .0,
+            );
+            dst.push(src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0);
+            dst.push(src[src_p].overflowing_sub(src[prev_row_p]).0);
             src_p += 4;
             prev_row_p += 4;
         }
-        Ok(dst_p)
+        Ok(())
     }
 
-    fn encode_bbp32_row3(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp32_row3(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let mut src_p = y as usize * width as usize * 4;
         let mut prev_row_p = (y - 1) as usize * width as usize * 4 + 4;
         for _ in 0..width - 1 {
-            dst[dst_p] = (0xff - src[src_p + 3])
-                .overflowing_sub(0xff - src[prev_row_p + 3])
-                .0;
-            dst[dst_p + 1] = src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0;
-            dst[dst_p + 2] = src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0;
-            dst[dst_p + 3] = src[src_p].overflowing_sub(src[prev_row_p]).0;
-            dst_p += 4;
+            dst.push(
+                (0xff - src[src_p + 3])
+                    .overflowing_sub(0xff - src[prev_row_p + 3])
+                    .0,
+            );
+            dst.push(src[src_p + 2].overflowing_sub(src[prev_row_p + 2]).0);
+            dst.push(src[src_p + 1].overflowing_sub(src[prev_row_p + 1]).0);
+            dst.push(src[src_p].overflowing_sub(src[prev_row_p]).0);
             src_p += 4;
             prev_row_p += 4;
         }
-        dst[dst_p] = 0xff - src[src_p + 3];
-        dst[dst_p + 1] = src[src_p + 2];
-        dst[dst_p + 2] = src[src_p + 1];
-        dst[dst_p + 3] = src[src_p];
-        dst_p += 4;
-        Ok(dst_p)
+        dst.push(0xff - src[src_p + 3]);
+        dst.push(src[src_p + 2]);
+        dst.push(src[src_p + 1]);
+        dst.push(src[src_p]);
+        Ok(())
     }
 
-    fn encode_bbp32_row4(
-        dst: &mut Vec<u8>,
-        mut dst_p: usize,
-        src: &[u8],
-        width: i16,
-        y: i16,
-    ) -> Result<usize> {
+    fn encode_bbp32_row4(dst: &mut Vec<u8>, src: &[u8], width: i16, y: i16) -> Result<()> {
         let src_p = y as usize * width as usize * 4;
         for offset in 0..4 {
             let mut src_c = src_p + 3 - offset as usize;
             let mut remaining = width;
             let value = src[src_c];
             src_c += 4;
-            dst[dst_p] = if offset == 0 { 0xff - value } else { value };
-            dst_p += 1;
+            dst.push(if offset == 0 { 0xff - value } else { value });
             remaining -= 1;
             if remaining == 0 {
                 continue;
             }
             let mut count = 0u8;
             loop {
-                if count as i16 >= remaining || count >= 255 || src[src_c] != value {
+                if count as i16 >= remaining || count == 255 || src[src_c] != value {
                     break;
                 }
                 src_c += 4;
                 count += 1;
             }
             if count > 0 {
-                dst[dst_p] = if offset == 0 { 0xff - value } else { value };
-                dst_p += 1;
-                dst[dst_p] = count;
-                dst_p += 1;
+                dst.push(if offset == 0 { 0xff - value } else { value });
+                dst.push(count);
                 remaining -= count as i16;
             }
             while remaining > 0 {
                 let value = src[src_c];
                 src_c += 4;
-                dst[dst_p] = if offset == 0 { 0xff - value } else { value };
-                dst_p += 1;
+                dst.push(if offset == 0 { 0xff - value } else { value });
                 remaining -= 1;
                 if remaining == 0 {
                     break;
                 }
                 let mut count = 0u8;
                 loop {
-                    if count as i16 >= remaining || count >= 255 || src[src_c] != value {
+                    if count as i16 >= remaining || count == 255 || src[src_c] != value {
                         break;
                     }
                     src_c += 4;
                     count += 1;
                 }
                 if count > 0 {
-                    dst[dst_p] = if offset == 0 { 0xff - value } else { value };
-                    dst_p += 1;
-                    dst[dst_p] = count;
-                    dst_p += 1;
+                    dst.push(if offset == 0 { 0xff - value } else { value });
+                    dst.push(count);
                     remaining -= count as i16;
                 }
             }
         }
-        Ok(dst_p)
-    }
-
-    fn encode_image_bbp32(src: &[u8], width: i16, height: i16, row_type: &[u8]) -> Result<Vec<u8>> {
-        let size = width as usize * height as usize * 4 + height as usize;
-        let mut dst = Vec::with_capacity(size);
-        dst.resize(size, 0);
-        let mut dst_p = 0;
-        for y in 0..height {
-            let data = row_type[y as usize];
-            dst[dst_p] = data;
-            dst_p += 1;
-            match data {
-                0 => {
-                    dst_p = Self::encode_bbp32_row0(&mut dst, dst_p, src, width, y)?;
-                }
-                1 => {
-                    dst_p = Self::encode_bbp32_row1(&mut dst, dst_p, src, width, y)?;
-                }
-                2 => {
-                    dst_p = Self::encode_bbp32_row2(&mut dst, dst_p, src, width, y)?;
-                }
-                3 => {
-                    dst_p = Self::encode_bbp32_row3(&mut dst, dst_p, src, width, y)?;
-                }
-                4 => {
-                    dst_p = Self::encode_bbp32_row4(&mut dst, dst_p, src, width, y)?;
-                }
-                _ => {
-                    return Err(anyhow::anyhow!("Invalid row type: {} on line {}", data, y));
-                }
-            }
-        }
-        Ok(dst)
+        Ok(())
     }
 
     fn rgba_to_rgb(src: &[u8]) -> Vec<u8> {
@@ -890,3 +2163,750 @@ impl Crx {
         dst
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pck::tests::build_pck;
+
+    /// Builds one row of RGB or RGBA pixels from `(value, run length)` pairs.
+    fn make_row(runs: &[([u8; 4], usize)], pixel_size: usize) -> Vec<u8> {
+        let mut row = Vec::new();
+        for (pixel, count) in runs {
+            for _ in 0..*count {
+                row.extend_from_slice(&pixel[..pixel_size]);
+            }
+        }
+        row
+    }
+
+    /// Encodes `row` as row type 4, followed by the same row reversed, and checks
+    /// that both decode back to the original pixels.
+    fn assert_row4_round_trip(row: &[u8], pixel_size: usize) {
+        let width = row.len() / pixel_size;
+        let mut reversed: Vec<u8> = row
+            .chunks_exact(pixel_size)
+            .rev()
+            .flatten()
+            .copied()
+            .collect();
+        let mut src = row.to_vec();
+        src.append(&mut reversed);
+        let encoded = Crx::encode_image(&src, width as i16, 2, &[4, 4], pixel_size as i8).unwrap();
+        let mut reader = RowReader {
+            inner: encoded.as_slice(),
+            offset: 0,
+        };
+        let mut cur = vec![0; row.len()];
+        let mut out = vec![0; row.len()];
+        for (y, expected) in src.chunks_exact(row.len()).enumerate() {
+            assert_eq!(reader.read_byte(y as i16).unwrap(), 4);
+            Crx::decode_row4(&mut cur, &mut reader, pixel_size, y as i16).unwrap();
+            Crx::convert_row(&mut out, &cur, pixel_size);
+            assert_eq!(out, expected, "row {} at {} bpp", y, pixel_size * 8);
+        }
+        assert!(reader.inner.is_empty(), "trailing bytes after the last row");
+    }
+
+    const A: [u8; 4] = [0x10, 0x20, 0x30, 0xff];
+    const B: [u8; 4] = [0x10, 0x21, 0x30, 0x80];
+    const C: [u8; 4] = [0xfe, 0x00, 0x7f, 0x00];
+
+    #[test]
+    fn row4_alternating_pixel_pairs() {
+        for pixel_size in [3, 4] {
+            let runs: Vec<_> = (0..20)
+                .map(|i| (if i % 2 == 0 { A } else { B }, 2))
+                .collect();
+            assert_row4_round_trip(&make_row(&runs, pixel_size), pixel_size);
+            let runs: Vec<_> = (0..21)
+                .map(|i| (if i % 2 == 0 { A } else { C }, 1))
+                .collect();
+            assert_row4_round_trip(&make_row(&runs, pixel_size), pixel_size);
+        }
+    }
+
+    #[test]
+    fn row4_run_of_1() {
+        for pixel_size in [3, 4] {
+            assert_row4_round_trip(&make_row(&[(A, 1)], pixel_size), pixel_size);
+            assert_row4_round_trip(&make_row(&[(A, 1), (B, 1), (C, 1)], pixel_size), pixel_size);
+        }
+    }
+
+    #[test]
+    fn row4_run_of_255() {
+        for pixel_size in [3, 4] {
+            assert_row4_round_trip(&make_row(&[(A, 255)], pixel_size), pixel_size);
+            assert_row4_round_trip(
+                &make_row(&[(B, 1), (A, 255), (C, 1)], pixel_size),
+                pixel_size,
+            );
+        }
+    }
+
+    #[test]
+    fn row4_run_of_256() {
+        for pixel_size in [3, 4] {
+            assert_row4_round_trip(&make_row(&[(A, 256)], pixel_size), pixel_size);
+            assert_row4_round_trip(
+                &make_row(&[(B, 1), (A, 256), (C, 256), (B, 1)], pixel_size),
+                pixel_size,
+            );
+        }
+    }
+
+    /// Decodes all rows of `encoded` and returns the error message.
+    fn decode_error(encoded: &[u8], width: i16, height: i16, bpp: i16) -> String {
+        let mut decoder = row_decoder(encoded, width, height, bpp).unwrap();
+        loop {
+            match decoder.next_row() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("malformed data decoded without an error"),
+                Err(e) => return e.to_string(),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_row_is_an_error() {
+        let encoded = [0, 1, 2, 3, 4, 5, 6, 0, 1, 2, 3];
+        let err = decode_error(&encoded, 2, 2, 0);
+        assert!(err.contains("row 1 at byte offset 8"), "{}", err);
+        // Also when the row type is missing.
+        let err = decode_error(&encoded[..7], 2, 2, 0);
+        assert!(err.contains("row 1 at byte offset 7"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_row_type_is_an_error() {
+        let encoded = [0, 1, 2, 3, 4, 5, 6, 5, 1, 2, 3, 4, 5, 6];
+        let err = decode_error(&encoded, 2, 2, 0);
+        assert!(
+            err.contains("Invalid row type 5 in row 1 at byte offset 7"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn row4_run_longer_than_the_row_is_an_error() {
+        // The first channel repeats its first value 5 more times in a row of 3 pixels.
+        let encoded = [4, 7, 7, 5, 1, 2, 3, 4, 5, 6];
+        let err = decode_error(&encoded, 3, 1, 0);
+        assert!(
+            err.contains(
+                "Run of 5 pixels exceeds the 2 remaining pixels in row 0 at byte offset 3"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn zero_size_is_an_error() {
+        for (width, height) in [(0i16, 1i16), (1, 0), (0, 0)] {
+            let mut file = Vec::new();
+            file.write_i32(MAGIC).unwrap();
+            for v in [0, 0, width, height, 2, 0x10, 0, 0] {
+                file.write_i16(v).unwrap();
+            }
+            file.write_i32(0).unwrap();
+            let err = CrxHeader::split_slice(&file).err().unwrap().to_string();
+            let size = format!("Invalid CRX image size: {}x{}", width, height);
+            assert!(err.contains(&size), "{}", err);
+            assert!(Crx::read_from_slice(&file).is_err());
+            assert!(CrxRowDecoder::from_slice(&file).is_err());
+        }
+    }
+
+    /// Compresses the encoded rows in `encoded` and prepares to decode them.
+    fn row_decoder(
+        encoded: &[u8],
+        width: i16,
+        height: i16,
+        bpp: i16,
+    ) -> Result<CrxRowDecoder<'static>> {
+        let compressed = utils::compress_zlib(encoded, 6).unwrap();
+        let header = CrxHeader {
+            inner_x: 0,
+            inner_y: 0,
+            width,
+            height,
+            version: 2,
+            flags: 0x10,
+            bpp,
+            unknown: 0,
+            clips: Vec::new(),
+            compressed_size: compressed.len() as u32,
+        };
+        CrxRowDecoder::with_header(header, std::io::Cursor::new(compressed))
+    }
+
+    #[test]
+    fn row_type_2_in_row_0_predicts_from_the_left() {
+        let encoded = [2, 10, 20, 30, 1, 1, 1, 2, 2, 2];
+        let mut decoder = row_decoder(&encoded, 3, 1, 0).unwrap();
+        assert_eq!(
+            decoder.next_row().unwrap().unwrap(),
+            [30, 20, 10, 31, 21, 11, 33, 23, 13]
+        );
+        assert!(decoder.next_row().unwrap().is_none());
+    }
+
+    #[test]
+    fn delta_row_types_in_row_0() {
+        for (bpp, pixel_size) in [(0, 3), (1, 4)] {
+            let (width, height) = (5, 2);
+            let src: Vec<u8> = (0..width * height * pixel_size)
+                .map(|i| (i * 37 + 11) as u8)
+                .collect();
+            for row_type in 1..=3 {
+                let encoded = Crx::encode_image(
+                    &src,
+                    width as i16,
+                    height as i16,
+                    &[row_type, row_type],
+                    pixel_size as i8,
+                )
+                .unwrap();
+                let mut decoder = row_decoder(&encoded, width as i16, height as i16, bpp).unwrap();
+                for expected in src.chunks_exact(width * pixel_size) {
+                    assert_eq!(decoder.next_row().unwrap().unwrap(), expected);
+                }
+                assert!(decoder.next_row().unwrap().is_none());
+            }
+        }
+    }
+
+    /// Creates a version 2 CRX image with `data` as its pixels, not yet encoded.
+    fn new_crx(width: i16, height: i16, bpp: i16, data: Vec<u8>) -> Crx<'static> {
+        Crx {
+            inner_x: 3,
+            inner_y: -2,
+            width,
+            height,
+            version: 2,
+            flags: 0,
+            bpp,
+            unknown: 0,
+            data,
+            compressed_data: Cow::Borrowed(&[]),
+            clips: Vec::new(),
+            encode_type: vec![0; height as usize],
+            codec: Codec::Zlib,
+            reencoded: false,
+        }
+    }
+
+    /// Builds a zlib compressed version 2 CRX file with opaque pixels.
+    fn build_crx(width: i16, height: i16, bpp: i16) -> Vec<u8> {
+        let pixel_size = if bpp == 0 { 3 } else { 4 };
+        let data: Vec<u8> = (0..width as usize * height as usize * pixel_size)
+            .map(|i| {
+                if pixel_size == 4 && i % 4 == 3 {
+                    0xff
+                } else {
+                    (i * 7) as u8
+                }
+            })
+            .collect();
+        let mut crx = new_crx(width, height, bpp, data);
+        crx.encode(&ImportOptions::default()).unwrap();
+        let mut out = Vec::new();
+        crx.write_to(&mut out).unwrap();
+        out
+    }
+
+    /// Images with gradients, flat areas, transparent borders and noise.
+    fn filter_fixtures(pixel_size: usize) -> Vec<(i16, i16, Vec<u8>)> {
+        let (width, height) = (48usize, 32usize);
+        let mut seed = 0x2545f491u32;
+        let mut noise = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        let mut fixtures = Vec::new();
+        for kind in 0..4 {
+            let mut data = Vec::with_capacity(width * height * pixel_size);
+            for y in 0..height {
+                for x in 0..width {
+                    let pixel = match kind {
+                        0 => [(x * 5) as u8, (y * 7) as u8, (x + y) as u8, 0xff],
+                        1 if (8..40).contains(&x) && (4..28).contains(&y) => {
+                            [(x * y) as u8, 0x40, (x * 3) as u8, (y * 8) as u8]
+                        }
+                        1 => [0, 0, 0, 0],
+                        2 => [(x / 8 * 40) as u8, (y / 8 * 60) as u8, 0x80, 0xff],
+                        _ => [noise(), noise() & 0xf0, (x * 4) as u8, noise() | 0x80],
+                    };
+                    data.extend_from_slice(&pixel[..pixel_size]);
+                }
+            }
+            fixtures.push((width as i16, height as i16, data));
+        }
+        fixtures
+    }
+
+    /// Encodes `data` with `row_filter` and returns the row types and compressed size.
+    fn encode_with(
+        (width, height, data): &(i16, i16, Vec<u8>),
+        bpp: i16,
+        codec: Codec,
+        row_filter: RowFilter,
+    ) -> (Vec<u8>, usize) {
+        let mut crx = new_crx(*width, *height, bpp, data.clone());
+        // The default zstd level allocates far too much for a unit test.
+        let level = match codec {
+            Codec::Zlib => 9,
+            Codec::Zstd => 12,
+        };
+        let options = ImportOptions {
+            row_filter,
+            codec: Some(codec),
+            level: Some(level),
+            ..Default::default()
+        };
+        crx.encode(&options).unwrap();
+        (crx.encode_type, crx.compressed_data.len())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn best_row_filter_is_never_larger_than_heuristic() {
+        for (bpp, pixel_size) in [(0, 3), (1, 4)] {
+            for (i, fixture) in filter_fixtures(pixel_size).iter().enumerate() {
+                for codec in [Codec::Zlib, Codec::Zstd] {
+                    let heuristic = encode_with(fixture, bpp, codec, RowFilter::Heuristic);
+                    let best = encode_with(fixture, bpp, codec, RowFilter::Best);
+                    assert!(
+                        best.1 <= heuristic.1,
+                        "{:?} at {} bpp: best {} > heuristic {}",
+                        codec,
+                        pixel_size * 8,
+                        best.1,
+                        heuristic.1
+                    );
+                    if i == 0 {
+                        // On the gradient, predicting from the row above beats the
+                        // smallest residuals by far.
+                        assert_ne!(best.0, heuristic.0, "{:?} at {} bpp", codec, pixel_size * 8);
+                        assert!(
+                            best.1 < heuristic.1,
+                            "{:?} at {} bpp: best {} >= heuristic {}",
+                            codec,
+                            pixel_size * 8,
+                            best.1,
+                            heuristic.1
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn mask_round_trip_in_every_format() {
+        let file = build_crx(5, 3, 1);
+        let mut crx = Crx::read_from_slice(&file).unwrap();
+        for (i, p) in crx.data.chunks_exact_mut(4).enumerate() {
+            p[3] = (i * 17 + 1) as u8;
+        }
+        let pixels = crx.data.clone();
+        let dir = tempfile::tempdir().unwrap();
+        for format in ImageFormat::ALL {
+            let color = dir.path().join(format!("color.{}", format.extension()));
+            let mask = dir.path().join(format!("mask.{}", format.extension()));
+            crx.export_image_with_mask(&color, &mask, MetadataMode::Skip)
+                .unwrap();
+            let mut imported = Crx::read_from_slice(&file).unwrap();
+            imported
+                .import_image_with_mask(&color, &mask, &ImportOptions::default())
+                .unwrap();
+            assert_eq!(imported.data, pixels, "{:?}", format);
+        }
+        let color = dir.path().join("unsupported.png");
+        let err = crx
+            .export_image_with_mask(&color, &dir.path().join("mask.gif"), MetadataMode::Sidecar)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Unsupported image format"),
+            "{}",
+            err
+        );
+        assert!(!color.exists());
+        assert!(!crate::metadata::sidecar_path(&color).exists());
+    }
+
+    /// Runs the 32bpp conversion with `transparent` on a 5x2 image that is visible
+    /// only in the middle of its first row, and returns the colors of every pixel.
+    fn convert_transparent(transparent: Transparent) -> Vec<[u8; 3]> {
+        const T: [u8; 4] = [9, 8, 7, 0];
+        let data = [[T, [100, 0, 0, 0xff], [0, 200, 0, 0x80], T, T], [T; 5]]
+            .concat()
+            .concat();
+        let options = ImportOptions {
+            transparent,
+            ..Default::default()
+        };
+        let out = Crx::convert_rgba(data.clone(), 5, 1, &options, None).unwrap();
+        for (p, src) in out.chunks_exact(4).zip(data.chunks_exact(4)) {
+            assert_eq!(p[3], src[3], "alpha must not change");
+        }
+        out.chunks_exact(4).map(|p| [p[0], p[1], p[2]]).collect()
+    }
+
+    #[test]
+    fn transparent_preserve_keeps_the_color() {
+        let t = [9, 8, 7];
+        assert_eq!(
+            convert_transparent(Transparent::Preserve),
+            [t, [100, 0, 0], [0, 200, 0], t, t, t, t, t, t, t]
+        );
+    }
+
+    #[test]
+    fn transparent_zero_clears_the_color() {
+        let z = [0, 0, 0];
+        assert_eq!(
+            convert_transparent(Transparent::Zero),
+            [z, [100, 0, 0], [0, 200, 0], z, z, z, z, z, z, z]
+        );
+    }
+
+    #[test]
+    fn transparent_bleed_fills_from_neighbours() {
+        let (red, green, mix) = ([100, 0, 0], [0, 200, 0], [50, 100, 0]);
+        // The first ring takes the average of its visible neighbours, the pixels on the
+        // right edge are only reached by the second ring.
+        assert_eq!(
+            convert_transparent(Transparent::Bleed),
+            [red, red, green, green, green, red, mix, mix, green, green]
+        );
+        let mut data = vec![5; 3 * 2 * 4];
+        data.chunks_exact_mut(4).for_each(|p| p[3] = 0);
+        let options = ImportOptions {
+            transparent: Transparent::Bleed,
+            ..Default::default()
+        };
+        let out = Crx::convert_rgba(data, 3, 1, &options, None).unwrap();
+        assert!(
+            out.iter().all(|&v| v == 0),
+            "unreachable pixels become black"
+        );
+    }
+
+    /// Half transparent, opaque and fully transparent RGBA pixels.
+    const MATTE_INPUT: [u8; 12] = [200, 100, 0, 128, 1, 2, 3, 0xff, 50, 50, 50, 0];
+
+    fn convert_matte(matte: Option<Matte>, original: Option<&[u8]>) -> Result<Vec<u8>> {
+        let options = ImportOptions {
+            matte,
+            ..Default::default()
+        };
+        Crx::convert_rgba(MATTE_INPUT.to_vec(), 3, 0, &options, original)
+    }
+
+    #[test]
+    fn matte_color_composites_translucent_pixels() {
+        let out = convert_matte(Some(Matte::Color([0, 0, 0xff])), None).unwrap();
+        assert_eq!(out, [100, 50, 127, 1, 2, 3, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn matte_original_composites_over_the_original_pixels() {
+        let original = [10, 20, 30, 40, 50, 60, 70, 80, 90];
+        let out = convert_matte(Some(Matte::Original), Some(&original)).unwrap();
+        assert_eq!(out, [105, 60, 15, 1, 2, 3, 70, 80, 90]);
+        let err = convert_matte(Some(Matte::Original), None).unwrap_err();
+        assert!(err.to_string().contains("--matte original"), "{}", err);
+    }
+
+    #[test]
+    fn no_matte_drops_the_alpha() {
+        let out = convert_matte(None, None).unwrap();
+        assert_eq!(out, [200, 100, 0, 1, 2, 3, 50, 50, 50]);
+    }
+
+    #[test]
+    fn matte_original_on_a_24bpp_import() {
+        let file = build_crx(3, 1, 0);
+        let mut crx = Crx::read_from_slice(&file).unwrap();
+        let original = crx.data.clone();
+        let options = ImportOptions {
+            matte: Some(Matte::Original),
+            ..Default::default()
+        };
+        crx.import_rgba("unused.bmp", 3, 1, MATTE_INPUT.to_vec(), &options)
+            .unwrap();
+        let expected: Vec<u8> = original[..3]
+            .iter()
+            .zip(&MATTE_INPUT[..3])
+            .map(|(&bg, &fg)| ((fg as u32 * 128 + bg as u32 * 127 + 127) / 255) as u8)
+            .chain([1, 2, 3])
+            .chain(original[6..].iter().copied())
+            .collect();
+        assert_eq!(crx.data, expected);
+        let mut out = Vec::new();
+        crx.write_to(&mut out).unwrap();
+        assert_eq!(Crx::read_from_slice(&out).unwrap().data, expected);
+    }
+
+    #[test]
+    fn blend_rgba_composites_over_the_current_pixels() {
+        let src = [200, 100, 0, 128, 9, 9, 9, 0, 1, 2, 3, 0xff];
+        let rgb = [0, 0, 0xff, 10, 20, 30, 40, 50, 60];
+        assert_eq!(
+            Crx::blend_rgba(&src, &rgb, 3),
+            [100, 50, 127, 10, 20, 30, 1, 2, 3]
+        );
+        let rgba = [0, 0, 0xff, 128, 10, 20, 30, 0, 40, 50, 60, 0x80];
+        assert_eq!(
+            Crx::blend_rgba(&src, &rgba, 4),
+            [134, 67, 85, 192, 0, 0, 0, 0, 1, 2, 3, 0xff]
+        );
+    }
+
+    /// Writes RGBA pixels to a PNG file in `dir`.
+    fn write_rgba(dir: &Path, width: u32, height: u32, data: &[u8]) -> std::path::PathBuf {
+        let path = dir.join("region.png");
+        Crx::write_image(&path, width, height, 4, data, None).unwrap();
+        path
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn import_region_cuts_off_parts_outside_the_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = write_rgba(
+            dir.path(),
+            2,
+            2,
+            &[1, 1, 1, 0xff, 2, 2, 2, 0xff, 3, 3, 3, 0xff, 4, 4, 4, 0xff],
+        );
+        let file = build_crx(4, 3, 0);
+        let original = Crx::read_from_slice(&file).unwrap().data;
+        let options = ImportOptions::default();
+        for (x, y, pixel, at) in [(-1, 2, [2, 2, 2], (0, 2)), (3, -1, [3, 3, 3], (3, 0))] {
+            let mut crx = Crx::read_from_slice(&file).unwrap();
+            crx.import_region(&image, x, y, false, &options).unwrap();
+            let mut expected = original.clone();
+            let start = (at.1 * 4 + at.0) * 3;
+            expected[start..start + 3].copy_from_slice(&pixel);
+            assert_eq!(crx.data, expected, "region at ({}, {})", x, y);
+        }
+        let mut crx = Crx::read_from_slice(&file).unwrap();
+        let err = crx
+            .import_region(&image, 4, 0, false, &options)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("outside of the 4x3 image"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn import_region_blends_and_applies_the_transparent_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = write_rgba(dir.path(), 2, 1, &[200, 100, 0, 128, 9, 9, 9, 0]);
+        let file = build_crx(3, 2, 1);
+        let mut crx = Crx::read_from_slice(&file).unwrap();
+        // Make the pixel below the transparent one transparent as well.
+        crx.data[(3 + 2) * 4 + 3] = 0;
+        let original = crx.data.clone();
+        let options = ImportOptions {
+            transparent: Transparent::Zero,
+            ..Default::default()
+        };
+        crx.import_region(&image, 1, 1, true, &options).unwrap();
+        let mut expected = original.clone();
+        let blended = Crx::blend_rgba(&[200, 100, 0, 128], &original[16..20], 4);
+        expected[16..20].copy_from_slice(&blended);
+        expected[20..24].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(crx.data, expected);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn import_region_rejects_resize_and_trim() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = write_rgba(dir.path(), 1, 1, &[1, 2, 3, 0xff]);
+        let file = build_crx(3, 2, 1);
+        for options in [
+            ImportOptions {
+                resize: true,
+                ..Default::default()
+            },
+            ImportOptions {
+                trim: true,
+                ..Default::default()
+            },
+        ] {
+            let mut crx = Crx::read_from_slice(&file).unwrap();
+            let err = crx
+                .import_region(&image, 0, 0, false, &options)
+                .unwrap_err();
+            assert!(err.to_string().contains("importing a region"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn resize_keeps_the_anchor_in_place() {
+        // The original 4x4 image covers (3, -2) to (7, 2) on screen.
+        let file = build_crx(4, 4, 0);
+        for (anchor, (w, h), bounds) in [
+            (Anchor::TopLeft, (6, 5), (3, -2, 9, 3)),
+            (Anchor::TopLeft, (2, 3), (3, -2, 5, 1)),
+            // Odd size differences round towards zero.
+            (Anchor::Center, (7, 6), (2, -3, 9, 3)),
+            (Anchor::Center, (1, 3), (4, -2, 5, 1)),
+            (Anchor::BottomRight, (6, 5), (1, -3, 7, 2)),
+            (Anchor::BottomRight, (2, 3), (5, -1, 7, 2)),
+        ] {
+            let pixels: Vec<u8> = (0..w * h * 4)
+                .map(|i| if i % 4 == 3 { 0xff } else { (i * 3) as u8 })
+                .collect();
+            let options = ImportOptions {
+                resize: true,
+                anchor,
+                ..Default::default()
+            };
+            let mut crx = Crx::read_from_slice(&file).unwrap();
+            crx.import_rgba("unused.bmp", w, h, pixels.clone(), &options)
+                .unwrap();
+            assert_eq!(crx.bounds(), bounds, "{:?} to {}x{}", anchor, w, h);
+
+            // Every pixel lands at its offset from the new top left corner on screen.
+            let (left, top) = (-4, -4);
+            let (cw, ch) = (16, 16);
+            let mut canvas = vec![0; cw * ch * 4];
+            crx.draw_onto(&mut canvas, left, top, cw as u32, ch as u32, 0, 0);
+            for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+                let x = (bounds.0 - left) as usize + i % w as usize;
+                let y = (bounds.1 - top) as usize + i / w as usize;
+                let at = (y * cw + x) * 4;
+                assert_eq!(&canvas[at..at + 4], pixel, "{:?} pixel {}", anchor, i);
+            }
+
+            let mut out = Vec::new();
+            crx.write_to(&mut out).unwrap();
+            let read = Crx::read_from_slice(&out).unwrap();
+            assert_eq!(read.bounds(), bounds);
+            assert_eq!(read.data, Crx::rgba_to_rgb(&pixels));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn trim_untrim_and_trim_again_round_trips() {
+        let file = build_crx(6, 5, 1);
+        let mut crx = Crx::read_from_slice(&file).unwrap();
+        // Only a 3x2 block at (2, 1) is visible.
+        for (i, p) in crx.data.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % 6, i / 6);
+            if !((2..5).contains(&x) && (1..3).contains(&y)) {
+                p[3] = 0;
+            }
+        }
+        crx.trim().unwrap();
+        assert_eq!(crx.bounds(), (5, -1, 8, 1));
+        let trimmed = crx.data.clone();
+        assert!(trimmed.chunks_exact(4).all(|p| p[3] == 0xff));
+
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("untrimmed.png");
+        crx.export_untrimmed(&png, MetadataMode::Embed).unwrap();
+        let options = ImportOptions {
+            trim: true,
+            ..Default::default()
+        };
+        let mut imported = Crx::read_from_slice(&file).unwrap();
+        imported.import_image(&png, &options).unwrap();
+        assert_eq!(imported.bounds(), (5, -1, 8, 1));
+        assert_eq!(imported.data, trimmed);
+    }
+
+    #[test]
+    fn trim_rejects_24bpp_images() {
+        let file = build_crx(4, 4, 0);
+        let mut crx = Crx::read_from_slice(&file).unwrap();
+        let pixels = vec![0xff; 4 * 4 * 4];
+        let options = ImportOptions {
+            trim: true,
+            ..Default::default()
+        };
+        let err = crx
+            .import_rgba("unused.bmp", 4, 4, pixels, &options)
+            .unwrap_err();
+        assert!(err.to_string().contains("--trim needs a 32bpp"), "{}", err);
+    }
+
+    #[test]
+    fn read_from_slice_borrows_the_pck_data() {
+        for bpp in [0, 1] {
+            let file = build_crx(5, 3, bpp);
+            let pck = build_pck(&[("b.txt", b"abc"), ("a.crx", &file)]);
+            let data = pck.get("A.CRX").unwrap().into_inner();
+            assert_eq!(data, file);
+
+            let (header, compressed) = CrxHeader::split_slice(data).unwrap();
+            assert_eq!((header.width, header.height, header.bpp), (5, 3, bpp));
+            assert_eq!((header.inner_x, header.inner_y), (3, -2));
+            assert_eq!(compressed.len(), header.compressed_size as usize);
+            assert!(data.as_ptr_range().contains(&compressed.as_ptr()));
+
+            let crx = Crx::read_from_slice(data).unwrap();
+            let Cow::Borrowed(borrowed) = &crx.compressed_data else {
+                panic!("compressed data was copied");
+            };
+            assert!(std::ptr::eq(*borrowed, compressed));
+            let mut out = Vec::new();
+            crx.write_to(&mut out).unwrap();
+            assert_eq!(out, file);
+
+            let mut decoder = CrxRowDecoder::from_slice(data).unwrap();
+            let row_size = 5 * if bpp == 0 { 3 } else { 4 };
+            for row in crx.data.chunks_exact(row_size) {
+                assert_eq!(decoder.next_row().unwrap().unwrap(), row);
+            }
+            assert!(decoder.next_row().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn truncated_slices_are_rejected() {
+        let file = build_crx(4, 4, 1);
+        let (header, _) = CrxHeader::split_slice(&file).unwrap();
+        let truncated = &file[..file.len() - 1];
+        let err = CrxHeader::split_slice(truncated).err().unwrap();
+        assert!(err.to_string().contains("Truncated CRX data"), "{}", err);
+        assert!(Crx::read_from_slice(truncated).is_err());
+        assert!(CrxRowDecoder::from_slice(truncated).is_err());
+        // Also when the header itself is cut off.
+        let header_size = file.len() - header.compressed_size as usize;
+        assert!(CrxHeader::split_slice(&file[..header_size - 1]).is_err());
+        assert!(Crx::read_from_slice(&file[..header_size]).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn unchanged_import_keeps_the_borrowed_data() {
+        let file = build_crx(6, 2, 1);
+        let pck = build_pck(&[("b.txt", b"abc"), ("a.crx", &file)]);
+        let data = pck.get("a.crx").unwrap().into_inner();
+        let mut crx = Crx::read_from_slice(data).unwrap();
+        let pixels = crx.data.clone();
+        crx.import_rgba("unused.bmp", 6, 2, pixels, &ImportOptions::default())
+            .unwrap();
+        assert!(matches!(crx.compressed_data, Cow::Borrowed(_)));
+        assert!(!crx.reencoded);
+        let mut out = Vec::new();
+        crx.write_to(&mut out).unwrap();
+        assert_eq!(out, file);
+    }
+}
@@ -4,7 +4,8 @@ pub trait ExtWriter {
     fn write_i16(&mut self, value: i16) -> Result<()>;
     fn write_i32(&mut self, value: i32) -> Result<()>;
     fn write_u32(&mut self, value: u32) -> Result<()>;
-    fn write_cstring_with_size(&mut self, value: &str, size: usize) -> Result<()>;
+    /// Writes `value` followed by null bytes up to `size` bytes.
+    fn write_cstring_with_size(&mut self, value: &[u8], size: usize) -> Result<()>;
 }
 
 impl<W: Write> ExtWriter for W {
@@ -23,8 +24,8 @@ impl<W: Write> ExtWriter for W {
         self.write_all(&bytes)
     }
 
-    fn write_cstring_with_size(&mut self, value: &str, size: usize) -> Result<()> {
-        let mut bytes = value.as_bytes().to_vec();
+    fn write_cstring_with_size(&mut self, value: &[u8], size: usize) -> Result<()> {
+        let mut bytes = value.to_vec();
         if bytes.len() >= size {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -40,7 +41,8 @@ pub trait ExtReader {
     fn read_i16(&mut self) -> Result<i16>;
     fn read_i32(&mut self) -> Result<i32>;
     fn read_u32(&mut self) -> Result<u32>;
-    fn read_cstring_with_size(&mut self, size: usize) -> Result<String>;
+    /// Reads `size` bytes and returns the bytes before the first null byte.
+    fn read_cstring_with_size(&mut self, size: usize) -> Result<Vec<u8>>;
 }
 
 impl<R: Read> ExtReader for R {
@@ -62,12 +64,11 @@ impl<R: Read> ExtReader for R {
         Ok(u32::from_le_bytes(buffer))
     }
 
-    fn read_cstring_with_size(&mut self, size: usize) -> Result<String> {
+    fn read_cstring_with_size(&mut self, size: usize) -> Result<Vec<u8>> {
         let mut buffer = vec![0; size];
         self.read_exact(&mut buffer)?;
         let null_pos = buffer.iter().position(|&c| c == 0).unwrap_or(size);
-        let s = str::from_utf8(&buffer[..null_pos])
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        Ok(s.to_owned())
+        buffer.truncate(null_pos);
+        Ok(buffer)
     }
 }
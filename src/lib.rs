@@ -0,0 +1,8 @@
+pub mod advdata;
+pub mod args;
+pub mod compose;
+pub mod crx;
+pub mod ext;
+pub mod metadata;
+pub mod pck;
+pub mod utils;
@@ -1,11 +1,14 @@
-pub mod advdata;
-pub mod args;
-pub mod crx;
-pub mod ext;
-pub mod pck;
-pub mod utils;
+use circus_crx_tool::{advdata, args, compose, crx, metadata, pck, utils};
+use std::io::Write;
 
-pub fn auto(input: &str) -> anyhow::Result<()> {
+pub fn auto(
+    input: &str,
+    options: &crx::ImportOptions,
+    metadata: metadata::MetadataMode,
+    format: utils::ImageFormat,
+    canvas: Option<(u32, u32)>,
+    encoding: pck::NameEncoding,
+) -> anyhow::Result<()> {
     let pb = std::path::PathBuf::from(input);
     let ext = pb
         .extension()
@@ -34,44 +37,53 @@ pub fn auto(input: &str) -> anyhow::Result<()> {
                     ))?,
             );
             utils::make_sure_dir_exists(&output_path)?;
-            let mut reader = pck::PckReader::new_from_file(&ori_pck_file_loc)?;
-            let mut writer = pck::PckWriter::new_from_file(
-                &output_path,
-                pck::PckWriter::calculate_header_size(reader.len() as u32),
-            )?;
-            for mut i in reader.iter_mut() {
-                let op = pb.join(&i.header.name).with_extension("png");
+            let reader = pck::PckReader::map_file(&ori_pck_file_loc, encoding)?;
+            let mut writer = pck::PckWriter::new_from_file(&output_path, encoding)?;
+            for i in reader.iter() {
+                let data = reader.data(i.header)?;
+                let base = pb.join(&i.header.name);
+                let op = utils::ImageFormat::ALL
+                    .iter()
+                    .map(|f| base.with_extension(f.extension()))
+                    .find(|p| p.exists());
                 let mut f = writer.add_file(&i.header.name)?;
-                if op.exists() {
-                    let size = i.header.size as u64;
-                    let mut crx = crx::Crx::read_from(&mut i, || Ok(size))?;
-                    crx.import_png(&op)?;
+                if let Some(op) = op {
+                    let mut crx = crx::Crx::read_from_slice(data)?;
+                    crx.import_image(&op, options)?;
                     crx.write_to(&mut f)?;
                 } else {
-                    eprintln!("File {} does not exist, skipping import.", op.display());
-                    std::io::copy(&mut i, &mut f)?;
+                    eprintln!("No image found for {}, skipping import.", base.display());
+                    f.write_all(data)?;
                 }
             }
-            writer.write_header()?;
+            writer.finish()?;
             eprintln!("Exported PCK to: {}", output_path.display());
             return Ok(());
         }
         for entry in std::fs::read_dir(pb)? {
             let entry = entry?;
-            auto(&entry.path().to_string_lossy())?;
+            auto(
+                &entry.path().to_string_lossy(),
+                options,
+                metadata,
+                format,
+                canvas,
+                encoding,
+            )?;
         }
         return Ok(());
     }
     if ext == "crx" {
-        let crx = crx::Crx::read_from_file(&pb)?;
         let mut pb2 = pb.clone();
         let mut failed = false;
         let mut removed = Vec::new();
         while pb2
             .file_name()
-            .is_some_and(|f| f.to_ascii_lowercase() != "advdata")
+            .is_some_and(|f| !f.eq_ignore_ascii_case("advdata"))
         {
-            pb2.file_name().map(|s| removed.push(s.to_owned()));
+            if let Some(s) = pb2.file_name() {
+                removed.push(s.to_owned())
+            }
             if !pb2.pop() {
                 failed = true;
                 eprintln!(
@@ -82,31 +94,36 @@ pub fn auto(input: &str) -> anyhow::Result<()> {
             }
         }
         if !failed {
-            pb2.file_name().map(|s| removed.push(s.to_owned()));
+            if let Some(s) = pb2.file_name() {
+                removed.push(s.to_owned())
+            }
             pb2.pop();
         }
         let output_path = if failed {
-            pb2.with_extension("png")
+            pb2.with_extension(format.extension())
         } else {
             let mut p = pb2.join("extracted");
-            loop {
-                match removed.pop() {
-                    Some(name) => p.push(name),
-                    None => break,
-                }
+            while let Some(name) = removed.pop() {
+                p.push(name);
             }
-            p.with_extension("png")
+            p.with_extension(format.extension())
         };
         utils::make_sure_dir_exists(&output_path)?;
-        crx.export_png(&output_path)?;
-    } else if ext == "png" {
-        if let Some(parent) = pb.parent() {
-            if parent
+        export_file(&pb, &output_path, metadata, canvas)?;
+    } else if utils::ImageFormat::from_path(&pb).is_some() {
+        if let Some(parent) = pb.parent()
+            && parent
                 .file_name()
                 .is_some_and(|f| advdata::ADV_DATA_MAP.contains_key(f.to_string_lossy().as_ref()))
-            {
-                return auto(parent.to_string_lossy().as_ref());
-            }
+        {
+            return auto(
+                parent.to_string_lossy().as_ref(),
+                options,
+                metadata,
+                format,
+                canvas,
+                encoding,
+            );
         }
         let filename = pb.file_name().ok_or(anyhow::anyhow!(
             "Failed to get file name from path: {}",
@@ -127,7 +144,7 @@ pub fn auto(input: &str) -> anyhow::Result<()> {
                 filename.display()
             ))?;
         let mut crx = crx::Crx::read_from_file(data)?;
-        crx.import_png(&pb)?;
+        crx.import_image(&pb, options)?;
         let output_path = advdata::BASE_PATH.join("patched").join(
             data.to_string_lossy()
                 .strip_prefix(&advdata::BASE_PATH.to_string_lossy().into_owned())
@@ -141,15 +158,17 @@ pub fn auto(input: &str) -> anyhow::Result<()> {
         utils::make_sure_dir_exists(&output_path)?;
         crx.write_to_file(&output_path)?;
     } else if ext == "pck" {
-        let mut pck = pck::PckReader::new_from_file(&pb)?;
+        let pck = pck::PckReader::map_file(&pb, encoding)?;
         let mut pb2 = pb.clone();
         let mut failed = false;
         let mut removed = Vec::new();
         while pb2
             .file_name()
-            .is_some_and(|f| f.to_ascii_lowercase() != "advdata")
+            .is_some_and(|f| !f.eq_ignore_ascii_case("advdata"))
         {
-            pb2.file_name().map(|s| removed.push(s.to_owned()));
+            if let Some(s) = pb2.file_name() {
+                removed.push(s.to_owned())
+            }
             if !pb2.pop() {
                 failed = true;
                 eprintln!(
@@ -160,90 +179,300 @@ pub fn auto(input: &str) -> anyhow::Result<()> {
             }
         }
         if !failed {
-            pb2.file_name().map(|s| removed.push(s.to_owned()));
+            if let Some(s) = pb2.file_name() {
+                removed.push(s.to_owned())
+            }
             pb2.pop();
         }
         let output_path = if failed {
             pb2
         } else {
             let mut p = pb2.join("extracted");
-            loop {
-                match removed.pop() {
-                    Some(name) => p.push(name),
-                    None => break,
-                }
+            while let Some(name) = removed.pop() {
+                p.push(name);
             }
             p
         };
+        pck.check_case_collisions()?;
         std::fs::create_dir_all(&output_path)?;
-        for mut i in pck.iter_mut() {
-            let len = i.header.size as u64;
-            let crx = crx::Crx::read_from(&mut i, || Ok(len))?;
-            let op = output_path.join(&i.header.name).with_extension("png");
-            crx.export_png(&op)?;
+        for i in pck.iter() {
+            let name = &i.header.name;
+            let op = output_path.join(name).with_extension(format.extension());
+            let result = pck
+                .data(i.header)
+                .and_then(|data| export_from(data, &op, metadata, canvas));
+            if let Err(e) = result {
+                eprintln!("Failed to export {}, skipping: {}", name, e);
+                let _ = std::fs::remove_file(&op);
+            }
         }
     }
     Ok(())
 }
 
-pub fn export_crx(input: &str, output: &str) -> anyhow::Result<()> {
+pub fn export_crx(
+    input: &str,
+    output: &str,
+    metadata: metadata::MetadataMode,
+    mask: Option<&str>,
+    untrim: bool,
+    canvas: Option<(u32, u32)>,
+) -> anyhow::Result<()> {
+    utils::make_sure_dir_exists(output)?;
+    match mask {
+        Some(mask) => {
+            let crx = crx::Crx::read_from_file(input)?;
+            utils::make_sure_dir_exists(mask)?;
+            crx.export_image_with_mask(output, mask, metadata)?;
+        }
+        None if untrim => crx::Crx::read_from_file(input)?.export_untrimmed(output, metadata)?,
+        None => export_file(input, output, metadata, canvas)?,
+    }
+    Ok(())
+}
+
+/// Exports the CRX file `input`. PNG files without a canvas are decoded row by row
+/// while the file is read.
+fn export_file<P: AsRef<std::path::Path> + ?Sized, Q: AsRef<std::path::Path> + ?Sized>(
+    input: &P,
+    output: &Q,
+    metadata: metadata::MetadataMode,
+    canvas: Option<(u32, u32)>,
+) -> anyhow::Result<()> {
+    if streams_png(output, canvas) {
+        let file = std::fs::File::open(input)?;
+        let mut file = std::io::BufReader::new(file);
+        let len = || Ok(std::fs::metadata(input)?.len());
+        return crx::CrxRowDecoder::new(&mut file, len)?.write_png(output, metadata);
+    }
     let crx = crx::Crx::read_from_file(input)?;
-    utils::make_sure_dir_exists(&output)?;
-    crx.export_png(&output)?;
+    export_image(&crx, output, metadata, canvas)
+}
+
+/// Exports the CRX file in `data`. PNG files without a canvas are decoded row by
+/// row straight into the output.
+fn export_from<P: AsRef<std::path::Path> + ?Sized>(
+    data: &[u8],
+    output: &P,
+    metadata: metadata::MetadataMode,
+    canvas: Option<(u32, u32)>,
+) -> anyhow::Result<()> {
+    if streams_png(output, canvas) {
+        return crx::CrxRowDecoder::from_slice(data)?.write_png(output, metadata);
+    }
+    let crx = crx::Crx::read_from_slice(data)?;
+    export_image(&crx, output, metadata, canvas)
+}
+
+/// Whether `output` can be written row by row while decoding.
+fn streams_png<P: AsRef<std::path::Path> + ?Sized>(output: &P, canvas: Option<(u32, u32)>) -> bool {
+    canvas.is_none() && utils::ImageFormat::from_path(output) == Some(utils::ImageFormat::Png)
+}
+
+/// Exports `crx`, placing it on a canvas of the given size if there is one.
+fn export_image<P: AsRef<std::path::Path> + ?Sized>(
+    crx: &crx::Crx,
+    output: &P,
+    metadata: metadata::MetadataMode,
+    canvas: Option<(u32, u32)>,
+) -> anyhow::Result<()> {
+    match canvas {
+        Some((width, height)) => crx.export_canvas(output, width, height, metadata),
+        None => crx.export_image(output, metadata),
+    }
+}
+
+pub fn import_crx(
+    origin: &str,
+    input: &str,
+    output: &str,
+    mask: Option<&str>,
+    options: &crx::ImportOptions,
+) -> anyhow::Result<()> {
+    let mut crx = crx::Crx::read_from_file(origin)?;
+    match mask {
+        Some(mask) => crx.import_image_with_mask(input, mask, options)?,
+        None => crx.import_image(input, options)?,
+    }
+    utils::make_sure_dir_exists(output)?;
+    crx.write_to_file(output)?;
     Ok(())
 }
 
-pub fn import_crx(origin: &str, input: &str, output: &str) -> anyhow::Result<()> {
+pub fn import_region(
+    origin: &str,
+    input: &str,
+    output: &str,
+    (x, y): (i32, i32),
+    blend: bool,
+    options: &crx::ImportOptions,
+) -> anyhow::Result<()> {
     let mut crx = crx::Crx::read_from_file(origin)?;
-    crx.import_png(input)?;
+    crx.import_region(input, x, y, blend, options)?;
+    utils::make_sure_dir_exists(output)?;
+    crx.write_to_file(output)?;
+    Ok(())
+}
+
+pub fn create_crx(args: &args::CreateArgs) -> anyhow::Result<()> {
+    let bpp = if args.bpp == 24 { 0 } else { 1 };
+    let mut metadata = metadata::CrxMetadata::read_for(&args.input)?.unwrap_or_default();
+    if let Some(version) = args.version {
+        metadata.version = version;
+    }
+    if let Some(inner_x) = args.inner_x {
+        metadata.inner_x = inner_x;
+    }
+    if let Some(inner_y) = args.inner_y {
+        metadata.inner_y = inner_y;
+    }
+    if !args.clips.is_empty() {
+        metadata.clips = args.clips.clone();
+    }
+    let crx = crx::Crx::from_image(&args.input, bpp, &metadata, &args.to_options())?;
+    utils::make_sure_dir_exists(&args.output)?;
+    crx.write_to_file(&args.output)?;
+    Ok(())
+}
+
+pub fn export_clips(input: &str, output: Option<&str>, images: Option<&str>) -> anyhow::Result<()> {
+    let crx = crx::Crx::read_from_file(input)?;
+    let output = match output {
+        Some(output) => std::path::PathBuf::from(output),
+        None => metadata::clips_path(input),
+    };
     utils::make_sure_dir_exists(&output)?;
+    std::fs::write(&output, serde_json::to_string_pretty(crx.clips())? + "\n")?;
+    if let Some(images) = images {
+        std::fs::create_dir_all(images)?;
+        let stem = std::path::Path::new(input)
+            .file_stem()
+            .ok_or(anyhow::anyhow!("No filename"))?
+            .to_string_lossy()
+            .into_owned();
+        for (i, clip) in crx.clips().iter().enumerate() {
+            let op = std::path::Path::new(images).join(format!("{}_{}.png", stem, i));
+            if let Err(e) = crx.export_clip_png(clip, &op) {
+                eprintln!("Failed to export clip {}: {}", i, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn import_clips(origin: &str, input: &str, output: &str) -> anyhow::Result<()> {
+    let mut crx = crx::Crx::read_from_file(origin)?;
+    let clips: Vec<crx::Clip> = serde_json::from_str(&std::fs::read_to_string(input)?)?;
+    crx.set_clips(clips);
+    utils::make_sure_dir_exists(output)?;
     crx.write_to_file(output)?;
     Ok(())
 }
 
-pub fn unpack(input: &str, output: &str) -> anyhow::Result<()> {
-    let mut pck = pck::PckReader::new_from_file(input)?;
+pub fn compose(
+    output: &str,
+    layers: &[String],
+    manifest: Option<&str>,
+    canvas: Option<(u32, u32)>,
+    encoding: pck::NameEncoding,
+) -> anyhow::Result<()> {
+    let mut all_layers = Vec::new();
+    let mut manifest_canvas = None;
+    if let Some(manifest) = manifest {
+        let manifest = compose::Manifest::read_from_file(manifest)?;
+        all_layers = manifest.layers;
+        manifest_canvas = manifest.canvas;
+    }
+    all_layers.extend(layers.iter().map(|l| compose::Layer::from(l.as_str())));
+    utils::make_sure_dir_exists(output)?;
+    compose::compose(&all_layers, canvas.or(manifest_canvas), encoding, output)
+}
+
+pub fn info(input: &str, encoding: pck::NameEncoding) -> anyhow::Result<()> {
+    let pb = std::path::PathBuf::from(input);
+    let ext = pb
+        .extension()
+        .unwrap_or(std::ffi::OsStr::new(""))
+        .to_ascii_lowercase();
+    if ext == "pck" {
+        let pck = pck::PckReader::map_file(&pb, encoding)?;
+        for i in pck.iter() {
+            if !i.header.name.to_ascii_lowercase().ends_with(".crx") {
+                continue;
+            }
+            match pck
+                .data(i.header)
+                .and_then(|data| crx::CrxHeader::split_slice(data))
+            {
+                Ok((header, _)) => println!("{}: {}", i.header.name, header),
+                Err(e) => eprintln!("{}: {}", i.header.name, e),
+            }
+        }
+    } else {
+        let header = crx::CrxHeader::read_from_file(&pb)?;
+        println!("{}: {}", pb.display(), header);
+    }
+    Ok(())
+}
+
+pub fn unpack(input: &str, output: &str, encoding: pck::NameEncoding) -> anyhow::Result<()> {
+    let pck = pck::PckReader::map_file(input, encoding)?;
+    pck.check_case_collisions()?;
     std::fs::create_dir_all(output)?;
-    for mut i in pck.iter_mut() {
+    for i in pck.iter() {
         let op = std::path::PathBuf::from(output).join(&i.header.name);
-        let f = std::fs::File::create(&op)?;
-        let mut writer = std::io::BufWriter::new(f);
-        std::io::copy(&mut i, &mut writer)?;
+        std::fs::write(&op, pck.data(i.header)?)?;
     }
     Ok(())
 }
 
-pub fn pack(input: &str, output: &str) -> anyhow::Result<()> {
+pub fn pack(input: &str, output: &str, encoding: pck::NameEncoding) -> anyhow::Result<()> {
     let input_path = std::path::PathBuf::from(input);
+    let mut files = Vec::new();
     if input_path.is_dir() {
-        let mut paths = Vec::new();
         for entry in std::fs::read_dir(input_path)? {
             let entry = entry?;
-            if entry.path().is_file() {
-                paths.push((entry.path(), entry.file_name()));
+            let meta = std::fs::metadata(entry.path())?;
+            if meta.is_file() {
+                files.push((entry.path(), entry.file_name(), meta.len()));
             }
         }
-        let mut pck = pck::PckWriter::new_from_file(
-            output,
-            pck::PckWriter::calculate_header_size(paths.len() as u32),
-        )?;
-        for entry in paths {
-            let file_name = entry.1;
-            let mut writer = pck.add_file(&file_name.to_string_lossy())?;
-            let mut f = std::fs::File::open(entry.0)?;
-            std::io::copy(&mut f, &mut writer)?;
-        }
-        pck.write_header()?;
     } else if input_path.is_file() {
-        let mut pck = pck::PckWriter::new_from_file(output, 0x800)?;
         let file_name = input_path
             .file_name()
-            .ok_or(anyhow::anyhow!("No filename"))?;
-        let mut writer = pck.add_file(&file_name.to_string_lossy())?;
-        let mut f = std::fs::File::open(input_path)?;
+            .ok_or(anyhow::anyhow!("No filename"))?
+            .to_owned();
+        let len = std::fs::metadata(&input_path)?.len();
+        files.push((input_path, file_name, len));
+    } else {
+        return Err(anyhow::anyhow!("Input not found: {}", input));
+    }
+    if files.is_empty() {
+        return Err(anyhow::anyhow!("No files to pack in {}", input));
+    }
+    let mut index = Vec::new();
+    for (path, file_name, len) in &files {
+        let size = u32::try_from(*len)
+            .map_err(|_| anyhow::anyhow!("{} is too large for a PCK file", path.display()))?;
+        // A lossy conversion would store U+FFFD instead of the original name.
+        let name = file_name.to_str().ok_or(anyhow::anyhow!(
+            "{} has a name that is not valid Unicode",
+            path.display()
+        ))?;
+        index.push((name.to_owned(), size));
+    }
+    let writer: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::BufWriter::new(std::io::stdout().lock()))
+    } else {
+        Box::new(std::io::BufWriter::new(std::fs::File::create(output)?))
+    };
+    let mut pck = pck::PckWriter::with_index(writer, &index, encoding)?;
+    for ((path, _, _), (name, _)) in files.iter().zip(&index) {
+        let mut writer = pck.add_file(name)?;
+        let mut f = std::fs::File::open(path)?;
         std::io::copy(&mut f, &mut writer)?;
-        pck.write_header()?;
     }
+    pck.finish()?;
     Ok(())
 }
 
@@ -251,7 +480,14 @@ fn main() {
     let args = args::Arg::parse();
     unsafe { std::env::set_var("RUST_LIB_BACKTRACE", "1") };
     if let Some(arg) = args.auto.as_ref() {
-        let e = match auto(&arg.input) {
+        let e = match auto(
+            &arg.input,
+            &args.import.to_options(),
+            arg.metadata,
+            arg.format,
+            arg.canvas,
+            args.encoding,
+        ) {
             Ok(_) => {
                 eprintln!("Auto operation completed successfully.");
                 false
@@ -270,16 +506,53 @@ fn main() {
     }
     if let Some(command) = args.command.as_ref() {
         match command {
-            args::Command::Export { input, output } => export_crx(input, output).unwrap(),
+            args::Command::Export {
+                input,
+                output,
+                metadata,
+                mask,
+                untrim,
+                canvas,
+            } => export_crx(input, output, *metadata, mask.as_deref(), *untrim, *canvas).unwrap(),
             args::Command::Import {
                 origin,
                 input,
                 output,
+                mask,
+                at,
+                blend,
+                import,
             } => {
-                import_crx(origin, input, output).unwrap();
+                let options = import.to_options();
+                match at {
+                    Some((x, y)) => {
+                        import_region(origin, input, output, (*x, *y), *blend, &options).unwrap()
+                    }
+                    None => import_crx(origin, input, output, mask.as_deref(), &options).unwrap(),
+                }
+            }
+            args::Command::Create(args) => create_crx(args).unwrap(),
+            args::Command::ExportClips {
+                input,
+                output,
+                images,
+            } => export_clips(input, output.as_deref(), images.as_deref()).unwrap(),
+            args::Command::ImportClips {
+                origin,
+                input,
+                output,
+            } => import_clips(origin, input, output).unwrap(),
+            args::Command::Compose {
+                output,
+                layers,
+                manifest,
+                canvas,
+            } => compose(output, layers, manifest.as_deref(), *canvas, args.encoding).unwrap(),
+            args::Command::Info { input } => info(input, args.encoding).unwrap(),
+            args::Command::Unpack { input, output } => {
+                unpack(input, output, args.encoding).unwrap()
             }
-            args::Command::Unpack { input, output } => unpack(input, output).unwrap(),
-            args::Command::Pack { input, output } => pack(input, output).unwrap(),
+            args::Command::Pack { input, output } => pack(input, output, args.encoding).unwrap(),
         }
     }
 }
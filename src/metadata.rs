@@ -0,0 +1,207 @@
+use crate::crx::Clip;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Keyword of the PNG text chunk holding the metadata.
+pub const PNG_TEXT_KEYWORD: &str = "CRX";
+
+/// CRX header fields that are not stored in the pixels of an exported image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrxMetadata {
+    pub inner_x: i16,
+    pub inner_y: i16,
+    pub version: i16,
+    pub flags: i16,
+    pub unknown: i16,
+    #[serde(default)]
+    pub clips: Vec<Clip>,
+}
+
+impl Default for CrxMetadata {
+    fn default() -> Self {
+        CrxMetadata {
+            inner_x: 0,
+            inner_y: 0,
+            version: 2,
+            flags: 0,
+            unknown: 0,
+            clips: Vec::new(),
+        }
+    }
+}
+
+/// Where metadata is stored when exporting an image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MetadataMode {
+    /// Embed in a PNG text chunk.
+    #[default]
+    Embed,
+    /// Write a .crx.json file next to the image.
+    Sidecar,
+    /// Do not store metadata.
+    Skip,
+}
+
+/// Returns the path of the JSON sidecar of an image, `foo.crx.json` for `foo.png`.
+///
+/// The `.crx.json` suffix keeps unrelated JSON files next to an image, like
+/// `foo.json`, from being read as its metadata.
+pub fn sidecar_path<P: AsRef<Path> + ?Sized>(image: &P) -> PathBuf {
+    image.as_ref().with_extension("crx.json")
+}
+
+/// Returns the default path of the clip table exported from a CRX file.
+///
+/// Uses a `.clips.json` suffix so it never overwrites the metadata sidecar of an
+/// exported image.
+pub fn clips_path<P: AsRef<Path> + ?Sized>(crx: &P) -> PathBuf {
+    crx.as_ref().with_extension("clips.json")
+}
+
+impl CrxMetadata {
+    pub fn validate(&self) -> Result<()> {
+        if self.version != 2 && self.version != 3 {
+            return Err(anyhow::anyhow!("Unsupported CRX version: {}", self.version));
+        }
+        if (self.flags & 0xF) > 1 {
+            return Err(anyhow::anyhow!(
+                "Unsupported CRX flags: 0x{:02X}",
+                self.flags
+            ));
+        }
+        if self.version < 3 && !self.clips.is_empty() {
+            return Err(anyhow::anyhow!("Clips are only supported by CRX version 3"));
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self> {
+        let metadata: Self = serde_json::from_str(s)?;
+        metadata.validate()?;
+        Ok(metadata)
+    }
+
+    pub fn write_sidecar<P: AsRef<Path> + ?Sized>(&self, image: &P) -> Result<()> {
+        std::fs::write(sidecar_path(image), self.to_json()? + "\n")?;
+        Ok(())
+    }
+
+    /// Reads the metadata of an image from its JSON sidecar, or from the PNG text chunk.
+    pub fn read_for<P: AsRef<Path> + ?Sized>(image: &P) -> Result<Option<Self>> {
+        let sidecar = sidecar_path(image);
+        if sidecar.is_file() {
+            let s = std::fs::read_to_string(&sidecar)?;
+            return Self::from_json(&s)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Invalid metadata in {}: {}", sidecar.display(), e));
+        }
+        let is_png = image
+            .as_ref()
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+        if !is_png {
+            return Ok(None);
+        }
+        let f = std::fs::File::open(image)?;
+        let reader = png::Decoder::new(std::io::BufReader::new(f)).read_info()?;
+        for chunk in &reader.info().uncompressed_latin1_text {
+            if chunk.keyword == PNG_TEXT_KEYWORD {
+                return Self::from_json(&chunk.text).map(Some).map_err(|e| {
+                    anyhow::anyhow!("Invalid metadata in {}: {}", image.as_ref().display(), e)
+                });
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crx::Crx;
+
+    fn metadata(inner_x: i16) -> CrxMetadata {
+        CrxMetadata {
+            inner_x,
+            inner_y: -7,
+            version: 3,
+            flags: 0x11,
+            unknown: 4,
+            clips: vec![Clip {
+                field_0: 1,
+                field_4: 2,
+                field_6: 3,
+                field_8: 4,
+                field_a: 5,
+                field_c: 6,
+                field_e: 7,
+            }],
+        }
+    }
+
+    /// Writes a 1x1 image, embedding `text` into PNG files.
+    fn write_image(path: &Path, text: Option<String>) {
+        Crx::write_image(path, 1, 1, 4, &[1, 2, 3, 4], text).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn embedded_text_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("a.png");
+        write_image(&png, Some(metadata(12).to_json().unwrap()));
+        assert_eq!(CrxMetadata::read_for(&png).unwrap(), Some(metadata(12)));
+
+        let plain = dir.path().join("b.png");
+        write_image(&plain, None);
+        assert_eq!(CrxMetadata::read_for(&plain).unwrap(), None);
+        let bmp = dir.path().join("c.bmp");
+        write_image(&bmp, None);
+        assert_eq!(CrxMetadata::read_for(&bmp).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sidecar_is_read_before_embedded_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("a.png");
+        write_image(&png, Some(metadata(12).to_json().unwrap()));
+        metadata(34).write_sidecar(&png).unwrap();
+        assert_eq!(sidecar_path(&png), dir.path().join("a.crx.json"));
+        assert_eq!(CrxMetadata::read_for(&png).unwrap(), Some(metadata(34)));
+
+        let bmp = dir.path().join("b.bmp");
+        write_image(&bmp, None);
+        metadata(56).write_sidecar(&bmp).unwrap();
+        assert_eq!(CrxMetadata::read_for(&bmp).unwrap(), Some(metadata(56)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn invalid_sidecar_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("a.png");
+        write_image(&png, Some(metadata(12).to_json().unwrap()));
+        let mut clips_in_v2 = metadata(0);
+        clips_in_v2.version = 2;
+        let mut bad_flags = metadata(0);
+        bad_flags.flags = 0x2;
+        let mut bad_version = metadata(0);
+        bad_version.version = 4;
+        for (invalid, message) in [
+            (clips_in_v2, "only supported by CRX version 3"),
+            (bad_flags, "Unsupported CRX flags: 0x02"),
+            (bad_version, "Unsupported CRX version: 4"),
+        ] {
+            std::fs::write(sidecar_path(&png), invalid.to_json().unwrap()).unwrap();
+            let err = CrxMetadata::read_for(&png).unwrap_err().to_string();
+            assert!(err.contains("a.crx.json"), "{}", err);
+            assert!(err.contains(message), "{}", err);
+        }
+    }
+}
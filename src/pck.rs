@@ -1,12 +1,175 @@
 use crate::ext::{ExtReader, ExtWriter};
 use anyhow::Result;
+use case_insensitive_hashmap::CaseInsensitiveHashMap;
+use encoding_rs::SHIFT_JIS;
+use std::fmt::Write as _;
 use std::io::{Read, Seek, Write};
 use std::iter::Iterator;
 use std::path::Path;
 
+/// Text encoding of entry names in PCK files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NameEncoding {
+    /// Shift-JIS as used by Windows (code page 932)
+    #[default]
+    Cp932,
+    Utf8,
+}
+
+impl NameEncoding {
+    /// Decodes a raw entry name into a name that can be used as a file name.
+    ///
+    /// Bytes that can not be decoded losslessly, `%` and characters that are not
+    /// allowed in file names are written as `%XX`, so [Self::encode] always gives
+    /// back the original bytes. A trailing `.` or space and the first letter of
+    /// Windows device names like `CON` or `COM1.crx` are escaped too, and an empty
+    /// name becomes `%00`.
+    pub fn decode(&self, raw: &[u8]) -> String {
+        if raw.is_empty() {
+            return "%00".to_owned();
+        }
+        let mut name = String::new();
+        let mut rest = raw;
+        while !rest.is_empty() {
+            let (unit, tail) = rest.split_at(self.char_len(rest).min(rest.len()));
+            match self.decode_char(unit) {
+                Some(c) if !needs_escape(c) => name.push(c),
+                _ => {
+                    for b in unit {
+                        let _ = write!(name, "%{:02X}", b);
+                    }
+                }
+            }
+            rest = tail;
+        }
+        if let Some(c @ ('.' | ' ')) = name.chars().last() {
+            name.pop();
+            let _ = write!(name, "%{:02X}", c as u8);
+        }
+        if is_device_name(&name) {
+            let c = name.remove(0);
+            name.insert_str(0, &format!("%{:02X}", c as u8));
+        }
+        name
+    }
+
+    /// Encodes a name returned by [Self::decode] back into raw bytes.
+    ///
+    /// `%XX` is only read as an escape if [Self::decode] gives back `name` for the
+    /// result. Any other name, such as `p%41.txt`, is stored with a literal `%`.
+    ///
+    /// Names that would contain a NUL byte are rejected, as the name is stored NUL
+    /// terminated. `%00` on its own is the empty name.
+    pub fn encode(&self, name: &str) -> Result<Vec<u8>> {
+        if name == "%00" {
+            return Ok(Vec::new());
+        }
+        let mut raw = self.encode_inner(name, true)?;
+        if self.decode(&raw) != name {
+            raw = self.encode_inner(name, false)?;
+        }
+        if raw.contains(&0) {
+            return Err(anyhow::anyhow!("Name {} contains a NUL byte", name));
+        }
+        Ok(raw)
+    }
+
+    fn encode_inner(&self, name: &str, unescape: bool) -> Result<Vec<u8>> {
+        let mut raw = Vec::new();
+        let mut chars = name.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' && unescape {
+                let hex = chars
+                    .as_str()
+                    .get(..2)
+                    .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()));
+                if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    raw.push(b);
+                    chars.nth(1);
+                    continue;
+                }
+            }
+            if c.is_ascii() {
+                // ASCII is the same in both encodings.
+                raw.push(c as u8);
+                continue;
+            }
+            let mut buf = [0; 4];
+            let s = c.encode_utf8(&mut buf);
+            match self {
+                NameEncoding::Cp932 => {
+                    let (bytes, _, had_errors) = SHIFT_JIS.encode(s);
+                    if had_errors {
+                        return Err(anyhow::anyhow!(
+                            "Character {:?} in {} can not be encoded in CP932",
+                            c,
+                            name
+                        ));
+                    }
+                    raw.extend_from_slice(&bytes);
+                }
+                NameEncoding::Utf8 => raw.extend_from_slice(s.as_bytes()),
+            }
+        }
+        Ok(raw)
+    }
+
+    /// Length of the character starting at `raw[0]`, judging by its first byte.
+    fn char_len(&self, raw: &[u8]) -> usize {
+        match (self, raw[0]) {
+            (NameEncoding::Cp932, 0x81..=0x9F | 0xE0..=0xFC) => 2,
+            (NameEncoding::Utf8, 0xC0..=0xDF) => 2,
+            (NameEncoding::Utf8, 0xE0..=0xEF) => 3,
+            (NameEncoding::Utf8, 0xF0..=0xF7) => 4,
+            _ => 1,
+        }
+    }
+
+    /// Decodes a single character, if it encodes back to the same bytes.
+    fn decode_char(&self, unit: &[u8]) -> Option<char> {
+        if let [b @ 0..=0x7F] = unit {
+            return Some(*b as char);
+        }
+        let s = match self {
+            NameEncoding::Cp932 => {
+                let s = SHIFT_JIS.decode_without_bom_handling_and_without_replacement(unit)?;
+                if SHIFT_JIS.encode(&s).0 != unit {
+                    return None;
+                }
+                s
+            }
+            NameEncoding::Utf8 => str::from_utf8(unit).ok()?.into(),
+        };
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+/// Returns whether Windows treats `name` as a device, ignoring any extension.
+fn is_device_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && matches!(stem.as_bytes()[3..], [b'1'..=b'9'])
+        }
+    }
+}
+
+fn needs_escape(c: char) -> bool {
+    c == '%' || c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|')
+}
+
 #[derive(Debug)]
 pub struct PckFileHeader {
+    /// Entry name as returned by [NameEncoding::decode]
     pub name: String,
+    /// Entry name as stored in the PCK file
+    pub raw_name: Vec<u8>,
     pub offset: u32,
     pub size: u32,
 }
@@ -38,21 +201,26 @@ impl<'a, R: Read + Seek> Read for PckFileReaderMut<'a, R> {
     }
 }
 
+/// Moves `current`, a position inside a file of `size` bytes, like [Seek::seek].
+fn seek_in_file(current: &mut u32, size: u32, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+    let new_pos = match pos {
+        std::io::SeekFrom::Start(offset) => offset,
+        std::io::SeekFrom::End(offset) => (size as i64 + offset) as u64,
+        std::io::SeekFrom::Current(offset) => (*current as i64 + offset) as u64,
+    };
+    if new_pos > size as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Seek position out of bounds",
+        ));
+    }
+    *current = new_pos as u32;
+    Ok(new_pos)
+}
+
 impl<'a, R: Read + Seek> Seek for PckFileReaderMut<'a, R> {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        let new_pos = match pos {
-            std::io::SeekFrom::Start(offset) => offset,
-            std::io::SeekFrom::End(offset) => (self.header.size as i64 + offset) as u64,
-            std::io::SeekFrom::Current(offset) => (self.pos as i64 + offset) as u64,
-        };
-        if new_pos > self.header.size as u64 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Seek position out of bounds",
-            ));
-        }
-        self.pos = new_pos as u32;
-        Ok(new_pos)
+        seek_in_file(&mut self.pos, self.header.size, pos)
     }
 
     fn stream_position(&mut self) -> std::io::Result<u64> {
@@ -65,6 +233,49 @@ impl<'a, R: Read + Seek> Seek for PckFileReaderMut<'a, R> {
     }
 }
 
+/// Reader of a file inside a PCK file on disk, see [PckReader::get].
+///
+/// It keeps its own position and reads with positioned reads, so any number of
+/// readers can be used at the same time. On Unix the file cursor is left alone, on
+/// Windows every read moves it. Readers from [PckReader::iter_mut] seek before every
+/// read, so they are not affected. Only available on Unix and Windows.
+#[cfg(any(unix, windows))]
+#[derive(Debug)]
+pub struct PckFileReaderAt<'a> {
+    pub header: &'a PckFileHeader,
+    file: &'a std::fs::File,
+    pos: u32,
+}
+
+#[cfg(any(unix, windows))]
+impl<'a> Read for PckFileReaderAt<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_to_read = buf.len().min((self.header.size - self.pos) as usize);
+        if bytes_to_read == 0 {
+            return Ok(0);
+        }
+        let offset = self.header.offset as u64 + self.pos as u64;
+        let buf = &mut buf[..bytes_to_read];
+        #[cfg(unix)]
+        let bytes_read = std::os::unix::fs::FileExt::read_at(self.file, buf, offset)?;
+        #[cfg(windows)]
+        let bytes_read = std::os::windows::fs::FileExt::seek_read(self.file, buf, offset)?;
+        self.pos += bytes_read as u32;
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl<'a> Seek for PckFileReaderAt<'a> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        seek_in_file(&mut self.pos, self.header.size, pos)
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.pos as u64)
+    }
+}
+
 pub struct PckFileReaderIter<'a, T: Iterator<Item = &'a PckFileHeader>> {
     header_iter: T,
 }
@@ -73,47 +284,84 @@ impl<'a, T: Iterator<Item = &'a PckFileHeader>> Iterator for PckFileReaderIter<'
     type Item = PckFileReader<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(header) = self.header_iter.next() {
-            Some(PckFileReader { header })
-        } else {
-            None
-        }
+        self.header_iter
+            .next()
+            .map(|header| PckFileReader { header })
     }
 }
 
+/// Lending iterator over readers of the files in a PCK file.
+///
+/// All readers share the seek position of the underlying reader, so every reader
+/// borrows the iterator and only one of them can be alive at a time. This is why
+/// it does not implement [Iterator] and can not be used in a `for` loop or with
+/// iterator adapters. Walk the files with `while let` instead:
+///
+/// ```
+/// # use circus_crx_tool::pck::{NameEncoding, PckReader, PckWriter};
+/// # use std::io::{Cursor, Read, Write};
+/// # let enc = NameEncoding::default();
+/// # let mut w = PckWriter::with_index(Vec::new(), &[("a.txt", 1), ("b.txt", 1)], enc)?;
+/// # w.add_file("a.txt")?.write_all(b"a")?;
+/// # w.add_file("b.txt")?.write_all(b"b")?;
+/// # let mut pck = PckReader::new(Cursor::new(w.finish()?), enc)?;
+/// let mut files = pck.iter_mut();
+/// while let Some(mut file) = files.next() {
+///     let mut data = String::new();
+///     file.read_to_string(&mut data)?;
+///     println!("{}: {}", file.header.name, data);
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Holding on to a reader while asking for the next one does not compile:
+///
+/// ```compile_fail,E0499
+/// # use circus_crx_tool::pck::{NameEncoding, PckReader, PckWriter};
+/// # use std::io::{Cursor, Read, Write};
+/// # let enc = NameEncoding::default();
+/// # let mut w = PckWriter::with_index(Vec::new(), &[("a.txt", 1), ("b.txt", 1)], enc)?;
+/// # w.add_file("a.txt")?.write_all(b"a")?;
+/// # w.add_file("b.txt")?.write_all(b"b")?;
+/// # let mut pck = PckReader::new(Cursor::new(w.finish()?), enc)?;
+/// let mut files = pck.iter_mut();
+/// let a = files.next().unwrap();
+/// let b = files.next().unwrap();
+/// println!("{} {}", a.header.name, b.header.name);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// To work with several files at once, use `get` on an archive in memory, memory
+/// mapped or on disk, or [PckReader::iter] and [PckReader::data].
 pub struct PckFileReaderMutIter<'a, R: Read + Seek, T: Iterator<Item = &'a PckFileHeader>> {
     header_iter: T,
     reader: &'a mut R,
 }
 
-impl<'a, R: Read + Seek, T: Iterator<Item = &'a PckFileHeader>> Iterator
-    for PckFileReaderMutIter<'a, R, T>
-{
-    type Item = PckFileReaderMut<'a, R>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(header) = self.header_iter.next() {
-            // SAFETY: We know that self.reader lives for the entire 'a lifetime
-            // and we're only returning one mutable reference at a time through the iterator
-            let reader_ref = unsafe { std::mem::transmute::<&mut R, &'a mut R>(self.reader) };
-            Some(PckFileReaderMut {
-                header,
-                reader: reader_ref,
-                pos: 0,
-            })
-        } else {
-            None
-        }
+impl<'a, R: Read + Seek, T: Iterator<Item = &'a PckFileHeader>> PckFileReaderMutIter<'a, R, T> {
+    /// Returns a reader for the next file, which must be dropped before calling
+    /// this again.
+    // Not Iterator::next, the item borrows the iterator.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<PckFileReaderMut<'_, R>> {
+        let header = self.header_iter.next()?;
+        Some(PckFileReaderMut {
+            header,
+            reader: self.reader,
+            pos: 0,
+        })
     }
 }
 
 pub struct PckReader<T: Read + Seek> {
     reader: T,
     file_headers: Vec<PckFileHeader>,
+    /// Index into `file_headers` by name, ignoring case like the engine does
+    name_index: CaseInsensitiveHashMap<usize>,
 }
 
 impl<T: Read + Seek> PckReader<T> {
-    pub fn new(mut reader: T) -> Result<Self> {
+    pub fn new(mut reader: T, encoding: NameEncoding) -> Result<Self> {
         let count = reader.read_u32()?;
         // (offset, size)
         let mut offset_list = Vec::new();
@@ -123,8 +371,10 @@ impl<T: Read + Seek> PckReader<T> {
             offset_list.push((offset, size));
         }
         let mut file_headers = Vec::new();
+        let mut name_index = CaseInsensitiveHashMap::new();
         for i in 0..count {
-            let name = reader.read_cstring_with_size(0x38)?;
+            let raw_name = reader.read_cstring_with_size(0x38)?;
+            let name = encoding.decode(&raw_name);
             let offset = reader.read_u32()?;
             let size = reader.read_u32()?;
             let ori_offset = offset_list[i as usize];
@@ -138,55 +388,200 @@ impl<T: Read + Seek> PckReader<T> {
                     size
                 ));
             }
-            file_headers.push(PckFileHeader { name, offset, size });
+            name_index.entry(name.as_str()).or_insert(i as usize);
+            file_headers.push(PckFileHeader {
+                name,
+                raw_name,
+                offset,
+                size,
+            });
+        }
+        let len = reader.seek(std::io::SeekFrom::End(0))?;
+        for header in &file_headers {
+            let end = header.offset as u64 + header.size as u64;
+            if end > len {
+                return Err(anyhow::anyhow!(
+                    "File {} at {}..{} is beyond the end of the PCK file",
+                    header.name,
+                    header.offset,
+                    end
+                ));
+            }
         }
         Ok(PckReader {
             reader,
             file_headers,
+            name_index,
         })
     }
 
+    /// Returns the index of the file named `name`, ignoring case. If several files
+    /// share a name, the first one is returned.
+    pub fn index_of<S: AsRef<str> + ?Sized>(&self, name: &S) -> Option<usize> {
+        self.name_index.get(name.as_ref()).copied()
+    }
+
+    /// Returns the header of the file at `index`.
+    pub fn header(&self, index: usize) -> Option<&PckFileHeader> {
+        self.file_headers.get(index)
+    }
+
+    /// Returns a reader for the file at `index`.
+    ///
+    /// The reader moves the position of the underlying reader, so it borrows the
+    /// whole PCK reader. In-memory, memory-mapped and on-disk PCK files can hand out
+    /// independent readers with `get` and `get_index` instead.
+    pub fn open(&mut self, index: usize) -> Option<PckFileReaderMut<'_, T>> {
+        let header = self.file_headers.get(index)?;
+        Some(PckFileReaderMut {
+            header,
+            reader: &mut self.reader,
+            pos: 0,
+        })
+    }
+
+    /// Fails if two files have names that only differ in case, as they would
+    /// overwrite each other when extracted on Windows or macOS.
+    pub fn check_case_collisions(&self) -> Result<()> {
+        for (i, header) in self.file_headers.iter().enumerate() {
+            let first = self.name_index[header.name.as_str()];
+            if first != i {
+                return Err(anyhow::anyhow!(
+                    "Files {} and {} only differ in case and can not both be extracted",
+                    self.file_headers[first].name,
+                    header.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether a file named `name` exists, ignoring case.
+    pub fn contains<S: AsRef<str> + ?Sized>(&self, name: &S) -> bool {
+        self.name_index.contains_key(name.as_ref())
+    }
+
     pub fn iter<'a>(&'a self) -> PckFileReaderIter<'a, impl Iterator<Item = &'a PckFileHeader>> {
-        return PckFileReaderIter {
+        PckFileReaderIter {
             header_iter: self.file_headers.iter(),
-        };
+        }
     }
 
+    /// Returns a lending iterator over readers of the files, see
+    /// [PckFileReaderMutIter].
     pub fn iter_mut<'a>(
         &'a mut self,
     ) -> PckFileReaderMutIter<'a, T, impl Iterator<Item = &'a PckFileHeader>> {
-        return PckFileReaderMutIter {
+        PckFileReaderMutIter {
             header_iter: self.file_headers.iter(),
             reader: &mut self.reader,
-        };
+        }
     }
 
     pub fn len(&self) -> usize {
         self.file_headers.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_headers.is_empty()
+    }
+}
+
+impl<B: AsRef<[u8]>> PckReader<std::io::Cursor<B>> {
+    /// Returns the contents of the file named `name`, ignoring case. If several
+    /// files share a name, the first one is returned.
+    ///
+    /// The result only borrows the archive, so several files can be read at once.
+    pub fn get<S: AsRef<str> + ?Sized>(&self, name: &S) -> Option<std::io::Cursor<&[u8]>> {
+        self.get_index(self.index_of(name)?)
+    }
+
+    /// Returns the contents of the file at `index`.
+    pub fn get_index(&self, index: usize) -> Option<std::io::Cursor<&[u8]>> {
+        let header = self.file_headers.get(index)?;
+        self.data(header).ok().map(std::io::Cursor::new)
+    }
+
+    /// Returns the contents of a file in an archive held in memory.
+    pub fn data(&self, header: &PckFileHeader) -> Result<&[u8]> {
+        let start = header.offset as usize;
+        let end = start + header.size as usize;
+        self.reader
+            .get_ref()
+            .as_ref()
+            .get(start..end)
+            .ok_or(anyhow::anyhow!(
+                "File {} at {}..{} is beyond the end of the PCK file",
+                header.name,
+                start,
+                end
+            ))
+    }
+}
+
+impl PckReader<std::io::Cursor<memmap2::Mmap>> {
+    /// Memory-maps the PCK file at `p`. Files inside it can then be read as slices
+    /// with [Self::data].
+    pub fn map_file<P: AsRef<Path> + ?Sized>(p: &P, encoding: NameEncoding) -> Result<Self> {
+        let file = std::fs::File::open(p)?;
+        // SAFETY: The map is read only. Changing the PCK file on disk while it is
+        // mapped is undefined behaviour, archives are assumed not to be written to
+        // while they are read.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::new(std::io::Cursor::new(map), encoding)
+    }
 }
 
 impl PckReader<std::io::BufReader<std::fs::File>> {
-    pub fn new_from_file<P: AsRef<Path> + ?Sized>(p: &P) -> Result<Self> {
+    pub fn new_from_file<P: AsRef<Path> + ?Sized>(p: &P, encoding: NameEncoding) -> Result<Self> {
         let file = std::fs::File::open(p)?;
         let reader = std::io::BufReader::new(file);
-        Self::new(reader)
+        Self::new(reader, encoding)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl PckReader<std::io::BufReader<std::fs::File>> {
+    /// Returns a reader for the file named `name`, ignoring case. If several files
+    /// share a name, the first one is returned.
+    ///
+    /// Readers keep their own position, so several files can be read at once.
+    pub fn get<S: AsRef<str> + ?Sized>(&self, name: &S) -> Option<PckFileReaderAt<'_>> {
+        self.get_index(self.index_of(name)?)
+    }
+
+    /// Returns a reader for the file at `index`.
+    pub fn get_index(&self, index: usize) -> Option<PckFileReaderAt<'_>> {
+        let header = self.file_headers.get(index)?;
+        Some(PckFileReaderAt {
+            header,
+            file: self.reader.get_ref(),
+            pos: 0,
+        })
     }
 }
 
-pub struct PckFileWriter<'a, T: Write + Seek> {
-    header: &'a mut PckFileHeader,
-    writer: &'a mut T,
+pub struct PckFileWriter<'a> {
+    writer: &'a mut dyn Write,
+    /// Bytes written so far
+    size: &'a mut u32,
+    /// Size given in an index that was written before the file
+    expected_size: Option<u32>,
 }
 
-impl<'a, T: Write + Seek> Write for PckFileWriter<'a, T> {
+impl<'a> Write for PckFileWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.writer.seek(std::io::SeekFrom::Start(
-            self.header.offset as u64 + self.header.size as u64,
-        ))?;
-        let bytes_written = self.writer.write(buf)?;
-        self.header.size += bytes_written as u32;
-        Ok(bytes_written)
+        let limit = self.expected_size.unwrap_or(u32::MAX);
+        if buf.len() as u64 > (limit - *self.size) as u64 {
+            let msg = match self.expected_size {
+                Some(size) => format!("File is larger than the {} bytes in the index", size),
+                None => "File is too large for a PCK file".to_owned(),
+            };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
+        self.writer.write_all(buf)?;
+        *self.size += buf.len() as u32;
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -194,108 +589,543 @@ impl<'a, T: Write + Seek> Write for PckFileWriter<'a, T> {
     }
 }
 
-pub struct PckWriter<T: Write + Seek + Read> {
+/// Writes PCK files from front to back without seeking, so the output can be a pipe.
+///
+/// When the names and sizes of all files are known up front, [Self::with_index]
+/// writes the index first and the files go straight to the output. Otherwise
+/// [Self::new] keeps the files in a temporary file until [Self::finish] has written
+/// the index.
+pub struct PckWriter<T: Write> {
     file: T,
     file_headers: Vec<PckFileHeader>,
-    header_max_size: u32,
+    encoding: NameEncoding,
+    /// Files written so far when the index is written last
+    body: Option<std::io::BufWriter<std::fs::File>>,
+    /// Number of files added so far
+    added: usize,
+    /// Bytes written to the last added file when the index was written first
+    written: u32,
 }
 
-impl<T: Write + Seek + Read> PckWriter<T> {
-    pub fn new(file: T, header_max_size: u32) -> Self {
-        PckWriter {
+impl<T: Write> PckWriter<T> {
+    pub fn new(file: T, encoding: NameEncoding) -> Result<Self> {
+        Ok(PckWriter {
             file,
             file_headers: Vec::new(),
-            header_max_size,
+            encoding,
+            body: Some(std::io::BufWriter::new(tempfile::tempfile()?)),
+            added: 0,
+            written: 0,
+        })
+    }
+
+    /// Writes the index for files with the given names and sizes. The files must
+    /// then be added in the same order.
+    pub fn with_index<S: AsRef<str>>(
+        file: T,
+        files: &[(S, u32)],
+        encoding: NameEncoding,
+    ) -> Result<Self> {
+        let mut writer = PckWriter {
+            file,
+            file_headers: Vec::with_capacity(files.len()),
+            encoding,
+            body: None,
+            added: 0,
+            written: 0,
+        };
+        let mut offset = calculate_header_size(files.len() as u32);
+        for (name, size) in files {
+            writer.push_header(name.as_ref(), offset, *size)?;
+            offset = offset
+                .checked_add(*size)
+                .ok_or(anyhow::anyhow!("Files are too large for a PCK file"))?;
         }
+        writer.write_index()?;
+        Ok(writer)
     }
 
     pub fn add_file<'a, S: AsRef<str> + ?Sized>(
         &'a mut self,
         name: &S,
-    ) -> Result<PckFileWriter<'a, T>> {
-        let offset = self
-            .file_headers
-            .last()
-            .map(|h| h.offset + h.size)
-            .unwrap_or(self.header_max_size);
-        self.file_headers.push(PckFileHeader {
-            name: name.as_ref().to_owned(),
-            offset,
-            size: 0,
-        });
-        self.check_header_capacity()?;
-        let header = self.file_headers.last_mut().unwrap();
+    ) -> Result<PckFileWriter<'a>> {
+        let name = name.as_ref();
+        if self.body.is_some() {
+            let offset = match self.file_headers.last() {
+                Some(h) => h
+                    .offset
+                    .checked_add(h.size)
+                    .ok_or(anyhow::anyhow!("Files are too large for a PCK file"))?,
+                None => 0,
+            };
+            self.push_header(name, offset, 0)?;
+            self.added += 1;
+            return Ok(PckFileWriter {
+                writer: self.body.as_mut().unwrap(),
+                size: &mut self.file_headers.last_mut().unwrap().size,
+                expected_size: None,
+            });
+        }
+        self.check_last_size()?;
+        let header = self.file_headers.get(self.added).ok_or(anyhow::anyhow!(
+            "{} is not in the index written before the files",
+            name
+        ))?;
+        if header.name != name {
+            return Err(anyhow::anyhow!(
+                "Expected {} next as in the index, got {}",
+                header.name,
+                name
+            ));
+        }
+        let expected_size = Some(header.size);
+        self.added += 1;
+        self.written = 0;
         Ok(PckFileWriter {
-            header,
             writer: &mut self.file,
+            size: &mut self.written,
+            expected_size,
         })
     }
 
-    pub fn write_header(&mut self) -> Result<()> {
-        self.file.seek(std::io::SeekFrom::Start(0))?;
-        self.file.write_u32(self.file_headers.len() as u32)?;
-        for header in &self.file_headers {
-            self.file.write_u32(header.offset)?;
-            self.file.write_u32(header.size)?;
-        }
-        for header in &self.file_headers {
-            self.file.write_cstring_with_size(&header.name, 0x38)?;
-            self.file.write_u32(header.offset)?;
-            self.file.write_u32(header.size)?;
+    /// Writes the index and files that have not been written yet, and returns the
+    /// output.
+    pub fn finish(mut self) -> Result<T> {
+        match self.body.take() {
+            Some(body) => {
+                let header_size = calculate_header_size(self.file_headers.len() as u32);
+                for header in &mut self.file_headers {
+                    header.offset = header
+                        .offset
+                        .checked_add(header_size)
+                        .filter(|o| o.checked_add(header.size).is_some())
+                        .ok_or(anyhow::anyhow!("Files are too large for a PCK file"))?;
+                }
+                self.write_index()?;
+                let mut body = body.into_inner().map_err(|e| e.into_error())?;
+                body.seek(std::io::SeekFrom::Start(0))?;
+                std::io::copy(&mut body, &mut self.file)?;
+            }
+            None => {
+                self.check_last_size()?;
+                if self.added != self.file_headers.len() {
+                    return Err(anyhow::anyhow!(
+                        "Only {} of {} files in the index were added",
+                        self.added,
+                        self.file_headers.len()
+                    ));
+                }
+            }
         }
         self.file.flush()?;
+        Ok(self.file)
+    }
+
+    fn push_header(&mut self, name: &str, offset: u32, size: u32) -> Result<()> {
+        let raw_name = encode_name(self.encoding, name)?;
+        self.file_headers.push(PckFileHeader {
+            name: name.to_owned(),
+            raw_name,
+            offset,
+            size,
+        });
         Ok(())
     }
 
-    fn check_header_capacity(&mut self) -> Result<()> {
-        if self.file_headers.len() as u32 * 0x48 + 4 < self.header_max_size {
-            return Ok(());
-        }
-        let new_header_capacity = self.header_max_size + 0x800;
-        self.file
-            .seek(std::io::SeekFrom::Start(self.header_max_size as u64))?;
-        let mut buffer1 = [0; 0x800];
-        let mut buffer2 = [0; 0x800];
-        let mut buffer1_size = 0x800;
-        let mut buffer1_start = self.header_max_size as u64;
-        let mut buffer2_size;
-        loop {
-            buffer2_size = self.file.read(&mut buffer2)?;
-            self.file.seek(std::io::SeekFrom::Start(buffer1_start))?;
-            self.file.write_all(&buffer1[..buffer1_size])?;
-            buffer1_size = buffer2_size;
-            std::mem::swap(&mut buffer1, &mut buffer2);
-            buffer1_start += buffer1_size as u64;
-            if buffer2_size == 0 {
-                break;
-            }
+    /// Checks that the last added file has the size given in the index.
+    fn check_last_size(&self) -> Result<()> {
+        match self.added.checked_sub(1).map(|i| &self.file_headers[i]) {
+            Some(header) if header.size != self.written => Err(anyhow::anyhow!(
+                "{} has {} bytes, but the index says {}",
+                header.name,
+                self.written,
+                header.size
+            )),
+            _ => Ok(()),
         }
-        for header in &mut self.file_headers {
-            header.offset += 0x800;
+    }
+
+    /// Writes the file count, the index and the padding up to the first file.
+    fn write_index(&mut self) -> Result<()> {
+        let count = self.file_headers.len() as u32;
+        let mut index = Vec::with_capacity(calculate_header_size(count) as usize);
+        index.write_u32(count)?;
+        for header in &self.file_headers {
+            index.write_u32(header.offset)?;
+            index.write_u32(header.size)?;
         }
-        self.header_max_size = new_header_capacity;
-        self.file.flush()?;
+        for header in &self.file_headers {
+            index.write_cstring_with_size(&header.raw_name, 0x38)?;
+            index.write_u32(header.offset)?;
+            index.write_u32(header.size)?;
+        }
+        index.resize(calculate_header_size(count) as usize, 0);
+        self.file.write_all(&index)?;
         Ok(())
     }
 }
 
-impl PckWriter<std::fs::File> {
-    pub fn calculate_header_size(file_count: u32) -> u32 {
-        let mut header_size = file_count * 0x48 + 4;
-        let a = header_size % 0x800;
-        if a != 0 {
-            header_size += 0x800 - a;
+/// Encodes `name` and checks that it fits into the index.
+fn encode_name(encoding: NameEncoding, name: &str) -> Result<Vec<u8>> {
+    let raw_name = encoding.encode(name)?;
+    if raw_name.len() >= 0x38 {
+        return Err(anyhow::anyhow!("Name is too long for a PCK file: {}", name));
+    }
+    Ok(raw_name)
+}
+
+/// Size of the index for `file_count` files, padded to a multiple of 0x800 bytes.
+pub fn calculate_header_size(file_count: u32) -> u32 {
+    let mut header_size = file_count * 0x48 + 4;
+    let a = header_size % 0x800;
+    if a != 0 {
+        header_size += 0x800 - a;
+    }
+    header_size
+}
+
+impl PckWriter<std::io::BufWriter<std::fs::File>> {
+    pub fn new_from_file<P: AsRef<Path> + ?Sized>(p: &P, encoding: NameEncoding) -> Result<Self> {
+        let file = std::fs::File::create(p)?;
+        Self::new(std::io::BufWriter::new(file), encoding)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a PCK file in memory holding `files` in order.
+    pub(crate) fn build_pck(files: &[(&str, &[u8])]) -> PckReader<std::io::Cursor<Vec<u8>>> {
+        let enc = NameEncoding::default();
+        let index: Vec<_> = files
+            .iter()
+            .map(|(name, data)| (*name, data.len() as u32))
+            .collect();
+        let mut writer = PckWriter::with_index(Vec::new(), &index, enc).unwrap();
+        for (name, data) in files {
+            writer.add_file(name).unwrap().write_all(data).unwrap();
         }
-        header_size
+        PckReader::new(std::io::Cursor::new(writer.finish().unwrap()), enc).unwrap()
+    }
+
+    fn read_all<R: Read>(mut reader: R) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn get_ignores_case() {
+        let pck = build_pck(&[("Face.crx", b"face"), ("body.CRX", b"body")]);
+        for name in ["Face.crx", "face.crx", "FACE.CRX"] {
+            assert_eq!(read_all(pck.get(name).unwrap()), b"face");
+            assert_eq!(
+                pck.header(pck.index_of(name).unwrap()).unwrap().name,
+                "Face.crx"
+            );
+        }
+        assert_eq!(read_all(pck.get("Body.crx").unwrap()), b"body");
+        assert!(pck.contains("BODY.crx"));
+    }
+
+    #[test]
+    fn get_returns_first_duplicate() {
+        let pck = build_pck(&[("a.crx", b"1"), ("A.crx", b"2")]);
+        assert_eq!(read_all(pck.get("a.CRX").unwrap()), b"1");
+        assert_eq!(read_all(pck.get_index(1).unwrap()), b"2");
+    }
+
+    #[test]
+    fn get_index_and_independent_entries() {
+        let pck = build_pck(&[("a.crx", b"aaa"), ("b.crx", b""), ("c.crx", b"cc")]);
+        let mut a = pck.get_index(0).unwrap();
+        let b = pck.get_index(1).unwrap();
+        let c = pck.get("c.crx").unwrap();
+        let mut buf = [0; 2];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"aa");
+        assert_eq!(read_all(c), b"cc");
+        assert_eq!(read_all(b), b"");
+        assert_eq!(read_all(a), b"a");
+    }
+
+    #[test]
+    fn open_reads_through_the_shared_reader() {
+        let mut pck = build_pck(&[("a.crx", b"aaa"), ("b.crx", b"bb")]);
+        let index = pck.index_of("B.CRX").unwrap();
+        assert_eq!(read_all(pck.open(index).unwrap()), b"bb");
+        assert_eq!(read_all(pck.open(0).unwrap()), b"aaa");
+        assert!(pck.open(2).is_none());
+    }
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    #[cfg_attr(miri, ignore)]
+    fn get_on_a_file() {
+        let pck = build_pck(&[("a.crx", b"aaa"), ("b.crx", b"bb"), ("c.crx", b"c")]);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(pck.reader.get_ref()).unwrap();
+        let pck = PckReader::new_from_file(file.path(), NameEncoding::default()).unwrap();
+        let mut a = pck.get("A.crx").unwrap();
+        let mut b = pck.get_index(1).unwrap();
+        let mut buf = [0; 1];
+        a.read_exact(&mut buf).unwrap();
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(read_all(&mut a), b"aa");
+        assert_eq!(read_all(&mut b), b"b");
+        a.rewind().unwrap();
+        assert_eq!(read_all(&mut a), b"aaa");
+        assert_eq!(read_all(pck.get("c.crx").unwrap()), b"c");
+        assert!(pck.get("d.crx").is_none());
+        assert!(pck.get_index(3).is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn map_file_returns_slices() {
+        let pck = build_pck(&[("a.crx", b"aaa"), ("b.crx", b"bb")]);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(pck.reader.get_ref()).unwrap();
+        let pck = PckReader::map_file(file.path(), NameEncoding::default()).unwrap();
+        let a = pck.get("a.crx").unwrap().into_inner();
+        let b = pck.get("B.crx").unwrap().into_inner();
+        assert_eq!((a, b), (&b"aaa"[..], &b"bb"[..]));
+        let files: Vec<_> = pck.iter().map(|f| pck.data(f.header).unwrap()).collect();
+        assert_eq!(files, [a, b]);
+    }
+
+    #[test]
+    fn entries_beyond_the_end_are_rejected() {
+        let pck = build_pck(&[("a.crx", b"aaa")]);
+        let mut data = pck.reader.into_inner();
+        data.pop();
+        let err = PckReader::new(std::io::Cursor::new(data), NameEncoding::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("beyond the end"));
+    }
+
+    #[test]
+    fn iter_mut_reads_every_file() {
+        let files: [(&str, &[u8]); 3] = [("a.crx", b"abc"), ("b.crx", b""), ("c.crx", b"de")];
+        let mut pck = build_pck(&files);
+        let mut iter = pck.iter_mut();
+        let mut expected = files.iter();
+        while let Some(mut file) = iter.next() {
+            let (name, data) = expected.next().unwrap();
+            assert_eq!(file.header.name, *name);
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, *data);
+            // Reading again after a seek gives the same bytes.
+            file.rewind().unwrap();
+            read.clear();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, *data);
+            assert_eq!(
+                file.seek(std::io::SeekFrom::End(0)).unwrap(),
+                data.len() as u64
+            );
+            assert!(
+                file.seek(std::io::SeekFrom::Start(data.len() as u64 + 1))
+                    .is_err()
+            );
+        }
+        assert!(expected.next().is_none());
+    }
+
+    const FILES: [(&str, &[u8]); 4] = [
+        ("a.crx", b"aaaa"),
+        ("empty.txt", b""),
+        ("\u{3042}.crx", b"kana"),
+        ("b.crx", b"bbbbbbbb"),
+    ];
+
+    fn assert_files(pck: &PckReader<std::io::Cursor<Vec<u8>>>, files: &[(&str, &[u8])]) {
+        assert_eq!(pck.len(), files.len());
+        for (file, (name, data)) in pck.iter().zip(files) {
+            assert_eq!(file.header.name, *name);
+            assert_eq!(pck.data(file.header).unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn with_index_round_trip() {
+        let pck = build_pck(&FILES);
+        assert_files(&pck, &FILES);
+        assert_eq!(pck.file_headers[0].offset, 0x800);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn temporary_body_round_trip() {
+        let enc = NameEncoding::default();
+        let mut writer = PckWriter::new(Vec::new(), enc).unwrap();
+        for (name, data) in FILES {
+            let mut f = writer.add_file(name).unwrap();
+            // Write in pieces, like std::io::copy does.
+            for chunk in data.chunks(3) {
+                f.write_all(chunk).unwrap();
+            }
+        }
+        let data = writer.finish().unwrap();
+        // Both modes give the same bytes.
+        assert_eq!(&data, build_pck(&FILES).reader.get_ref());
+        assert_files(
+            &PckReader::new(std::io::Cursor::new(data), enc).unwrap(),
+            &FILES,
+        );
+
+        let empty = PckWriter::new(Vec::new(), enc).unwrap().finish().unwrap();
+        assert_files(
+            &PckReader::new(std::io::Cursor::new(empty), enc).unwrap(),
+            &[],
+        );
+    }
+
+    #[test]
+    fn with_index_rejects_files_larger_than_the_index() {
+        let enc = NameEncoding::default();
+        let mut writer = PckWriter::with_index(Vec::new(), &[("a.crx", 3)], enc).unwrap();
+        let mut f = writer.add_file("a.crx").unwrap();
+        f.write_all(b"ab").unwrap();
+        let err = f.write_all(b"cd").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(
+            err.to_string().contains("larger than the 3 bytes"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn with_index_rejects_files_smaller_than_the_index() {
+        let enc = NameEncoding::default();
+        let index = [("a.crx", 3), ("b.crx", 1)];
+        let mut writer = PckWriter::with_index(Vec::new(), &index, enc).unwrap();
+        writer.add_file("a.crx").unwrap().write_all(b"ab").unwrap();
+        let err = writer.add_file("b.crx").err().unwrap();
+        assert_eq!(err.to_string(), "a.crx has 2 bytes, but the index says 3");
+
+        let mut writer = PckWriter::with_index(Vec::new(), &index[..1], enc).unwrap();
+        writer.add_file("a.crx").unwrap().write_all(b"a").unwrap();
+        let err = writer.finish().err().unwrap();
+        assert_eq!(err.to_string(), "a.crx has 1 bytes, but the index says 3");
+    }
+
+    #[test]
+    fn with_index_rejects_missing_and_unexpected_files() {
+        let enc = NameEncoding::default();
+        let index = [("a.crx", 1), ("b.crx", 1)];
+        let mut writer = PckWriter::with_index(Vec::new(), &index, enc).unwrap();
+        writer.add_file("a.crx").unwrap().write_all(b"a").unwrap();
+        let err = writer.finish().err().unwrap();
+        assert_eq!(err.to_string(), "Only 1 of 2 files in the index were added");
+
+        let mut writer = PckWriter::with_index(Vec::new(), &index, enc).unwrap();
+        let err = writer.add_file("b.crx").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Expected a.crx next as in the index, got b.crx"
+        );
+
+        let mut writer = PckWriter::with_index(Vec::new(), &index[..1], enc).unwrap();
+        writer.add_file("a.crx").unwrap().write_all(b"a").unwrap();
+        let err = writer.add_file("c.crx").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "c.crx is not in the index written before the files"
+        );
+    }
+
+    #[test]
+    fn case_collisions() {
+        assert!(
+            build_pck(&[("a.crx", b"1"), ("b.crx", b"2")])
+                .check_case_collisions()
+                .is_ok()
+        );
+        let err = build_pck(&[("a.crx", b"1"), ("b.crx", b"2"), ("A.CRX", b"3")])
+            .check_case_collisions()
+            .unwrap_err();
+        assert!(err.to_string().contains("a.crx and A.CRX"));
+    }
+
+    #[test]
+    fn missing_entries() {
+        let pck = build_pck(&[("a.crx", b"a")]);
+        assert!(pck.get("b.crx").is_none());
+        assert!(pck.get("").is_none());
+        assert!(!pck.contains("b.crx"));
+        assert!(pck.index_of("b.crx").is_none());
+        assert!(pck.header(1).is_none());
+        assert!(pck.get_index(1).is_none());
+        assert!(pck.get_index(usize::MAX).is_none());
+        assert!(build_pck(&[]).get_index(0).is_none());
+    }
+
+    #[test]
+    fn name_round_trip() {
+        for enc in [NameEncoding::Cp932, NameEncoding::Utf8] {
+            for raw in [
+                &b"face.crx"[..],
+                b"100%.crx",
+                b"a/b:c.crx",
+                b"\x82\xa0.crx",
+                b"\xff\x01.crx",
+                b".",
+                b"..",
+                b"...",
+                b"",
+                b"a. ",
+                b"con",
+                b"Com1.crx",
+                b"lpt9",
+            ] {
+                let name = enc.decode(raw);
+                assert_eq!(enc.encode(&name).unwrap(), raw, "{:?} as {}", enc, name);
+            }
+        }
+    }
+
+    #[test]
+    fn dot_names_are_escaped() {
+        let enc = NameEncoding::default();
+        assert_eq!(enc.decode(b"."), "%2E");
+        assert_eq!(enc.decode(b".."), ".%2E");
+        assert_eq!(enc.decode(b"..."), "..%2E");
+        assert_eq!(enc.decode(b".hidden"), ".hidden");
+        assert_eq!(enc.decode(b"a.crx."), "a.crx%2E");
+        assert_eq!(enc.decode(b"a.crx "), "a.crx%20");
+    }
+
+    #[test]
+    fn windows_unsafe_names_are_escaped() {
+        let enc = NameEncoding::default();
+        assert_eq!(enc.decode(b""), "%00");
+        assert_eq!(enc.decode(b"CON"), "%43ON");
+        assert_eq!(enc.decode(b"nul.crx"), "%6Eul.crx");
+        assert_eq!(enc.decode(b"COM1.crx"), "%43OM1.crx");
+        assert_eq!(enc.decode(b"console.crx"), "console.crx");
+        assert_eq!(enc.decode(b"COM10.crx"), "COM10.crx");
+        assert_eq!(enc.decode(b"LPT.crx"), "LPT.crx");
+    }
+
+    #[test]
+    fn nul_is_rejected() {
+        let enc = NameEncoding::default();
+        assert_eq!(enc.encode("%00").unwrap(), b"");
+        assert!(enc.encode("a%00.crx").is_err());
+        assert!(enc.encode("a\0.crx").is_err());
     }
 
-    pub fn new_from_file<P: AsRef<Path> + ?Sized>(p: &P, header_max_size: u32) -> Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(p)?;
-        Ok(Self::new(file, header_max_size))
+    #[test]
+    fn literal_percent_in_host_names() {
+        let enc = NameEncoding::default();
+        assert_eq!(enc.encode("p%41.txt").unwrap(), b"p%41.txt");
+        assert_eq!(enc.encode("100%").unwrap(), b"100%");
+        assert_eq!(enc.encode("a%zz.txt").unwrap(), b"a%zz.txt");
+        assert_eq!(enc.encode("a%25.txt").unwrap(), b"a%.txt");
     }
 }
@@ -13,15 +13,105 @@ pub fn make_sure_dir_exists<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn compress_data(data: &[u8]) -> std::io::Result<Vec<u8>> {
-    let mut encoder = Encoder::new(Vec::new(), 22)?;
+/// Image file formats that CRX files can be exported to and imported from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Tga,
+    Bmp,
+    Qoi,
+    /// Lossless WebP
+    Webp,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 5] = [
+        ImageFormat::Png,
+        ImageFormat::Tga,
+        ImageFormat::Bmp,
+        ImageFormat::Qoi,
+        ImageFormat::Webp,
+    ];
+
+    /// Detects the format from the extension of `path`.
+    pub fn from_path<P: AsRef<Path> + ?Sized>(path: &P) -> Option<Self> {
+        let ext = path.as_ref().extension()?;
+        Self::ALL
+            .into_iter()
+            .find(|f| ext.eq_ignore_ascii_case(f.extension()))
+    }
+
+    /// Whether the format can store grayscale images. QOI only stores RGB and RGBA.
+    pub fn has_grayscale(&self) -> bool {
+        *self != ImageFormat::Qoi
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Tga => "tga",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Qoi => "qoi",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Compression format of CRX image data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Codec {
+    Zlib,
+    Zstd,
+}
+
+impl Codec {
+    /// Detects the codec from the first bytes of compressed data.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else {
+            Codec::Zlib
+        }
+    }
+
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Codec::Zlib => 9,
+            Codec::Zstd => 22,
+        }
+    }
+}
+
+pub fn compress_data(data: &[u8], codec: Codec, level: Option<i32>) -> std::io::Result<Vec<u8>> {
+    let level = level.unwrap_or(codec.default_level());
+    match codec {
+        Codec::Zlib => compress_zlib(data, level),
+        Codec::Zstd => compress_zstd(data, level),
+    }
+}
+
+pub fn compress_zlib(data: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+    if !(0..=9).contains(&level) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid zlib compression level: {}", level),
+        ));
+    }
+    let mut encoder =
+        flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(level as u32));
     encoder.write_all(data)?;
-    Ok(encoder.finish()?)
+    encoder.finish()
 }
 
-pub fn decompress_data(data: &[u8]) -> std::io::Result<Vec<u8>> {
-    let mut decoder = zstd::Decoder::new(data)?;
-    let mut decompressed_data = Vec::new();
-    std::io::copy(&mut decoder, &mut decompressed_data)?;
-    Ok(decompressed_data)
+pub fn compress_zstd(data: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+    if !zstd::compression_level_range().contains(&level) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid zstd compression level: {}", level),
+        ));
+    }
+    let mut encoder = Encoder::new(Vec::new(), level)?;
+    encoder.write_all(data)?;
+    encoder.finish()
 }
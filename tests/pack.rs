@@ -0,0 +1,79 @@
+#![cfg(not(miri))]
+
+use circus_crx_tool::pck::{NameEncoding, PckReader};
+use std::process::Command;
+
+fn tool() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_circus-crx-tool"))
+}
+
+#[test]
+fn pack_to_stdout() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("in");
+    std::fs::create_dir(&input).unwrap();
+    std::fs::write(input.join("a.crx"), b"aaa").unwrap();
+    std::fs::write(input.join("b.txt"), b"bb").unwrap();
+    let output = tool().arg("pack").arg(&input).arg("-").output().unwrap();
+    assert!(output.status.success());
+
+    let file = dir.path().join("out.pck");
+    let status = tool().arg("pack").arg(&input).arg(&file).status().unwrap();
+    assert!(status.success());
+    assert_eq!(output.stdout, std::fs::read(&file).unwrap());
+
+    let pck = PckReader::new(std::io::Cursor::new(output.stdout), NameEncoding::default()).unwrap();
+    let mut files: Vec<_> = pck
+        .iter()
+        .map(|f| (f.header.name.clone(), pck.data(f.header).unwrap().to_vec()))
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            ("a.crx".to_owned(), b"aaa".to_vec()),
+            ("b.txt".to_owned(), b"bb".to_vec())
+        ]
+    );
+}
+
+#[test]
+fn pack_fails_without_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.pck");
+    let missing = dir.path().join("missing");
+    let empty = dir.path().join("empty");
+    std::fs::create_dir(&empty).unwrap();
+    for (input, message) in [(missing, "Input not found"), (empty, "No files to pack")] {
+        let result = tool()
+            .arg("pack")
+            .arg(&input)
+            .arg(&output)
+            .output()
+            .unwrap();
+        assert!(!result.status.success());
+        assert!(String::from_utf8_lossy(&result.stderr).contains(message));
+        assert!(!output.exists());
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn pack_fails_on_names_that_are_not_unicode() {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("in");
+    std::fs::create_dir(&input).unwrap();
+    let name = std::ffi::OsStr::from_bytes(b"\x82\xa0.txt");
+    std::fs::write(input.join(name), b"a").unwrap();
+    let output = dir.path().join("out.pck");
+    let result = tool()
+        .arg("pack")
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .unwrap();
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("not valid Unicode"));
+    assert!(!output.exists());
+}